@@ -0,0 +1,66 @@
+use clade::tree::{build_pruned_tree, GtdbTree, Node};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+const TREE_LEAVES: usize = 200_000;
+
+fn node(id: usize, parent: usize) -> Node {
+    Node {
+        node: id,
+        parent,
+        name: format!("RS_GCF_{:09}.1", id),
+        length: 0.01,
+        bootstrap: None,
+        rank: None,
+        label: None,
+        children: Vec::new(),
+    }
+}
+
+/// Builds a random binary tree with `leaves` tips by splitting leaf ranges at
+/// pseudo-random points, numbering nodes the way `parse_tree` does (root is 1).
+fn random_tree(leaves: usize) -> (GtdbTree, Vec<usize>) {
+    let mut seed: u64 = 42;
+    let mut next = move || {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 33) as usize
+    };
+
+    let mut nodes = Vec::with_capacity(2 * leaves);
+    let mut tips = Vec::with_capacity(leaves);
+    let mut stack = vec![(0usize, leaves)];
+    while let Some((parent, size)) = stack.pop() {
+        let id = nodes.len() + 1;
+        nodes.push(node(id, parent));
+        if size == 1 {
+            tips.push(id);
+        } else {
+            let left = 1 + next() % (size - 1);
+            stack.push((id, size - left));
+            stack.push((id, left));
+        }
+    }
+
+    (GtdbTree::from_nodes(nodes).expect("tree has a root"), tips)
+}
+
+fn bench_prune(c: &mut Criterion) {
+    let (tree, tips) = random_tree(TREE_LEAVES);
+
+    let mut group = c.benchmark_group("build_pruned_tree");
+    group.sample_size(10);
+    for selected in [1_000, 10_000, 100_000] {
+        let step = tips.len() / selected;
+        let leaf_nodes: Vec<usize> = tips.iter().step_by(step).take(selected).copied().collect();
+        group.bench_with_input(
+            BenchmarkId::from_parameter(selected),
+            &leaf_nodes,
+            |b, leaf_nodes| b.iter(|| build_pruned_tree(&tree, leaf_nodes)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_prune);
+criterion_main!(benches);
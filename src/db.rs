@@ -2,6 +2,7 @@ use rusqlite::{params, Connection, Result};
 
 pub fn create_tables(conn: &Connection) -> Result<()> {
     create_genome_taxonomy_table(conn)?;
+    create_genome_metadata_table(conn)?;
     create_gtdb_tree_tables(conn, &["archaea", "bacteria"])?;
     Ok(())
 }
@@ -51,6 +52,95 @@ pub fn create_genome_taxonomy_table(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
+/// Quality and representative metadata of a genome, as read from the GTDB metadata files.
+#[derive(Debug, Clone, Default)]
+pub struct GenomeMetadata {
+    pub accession: String,
+    pub checkm_completeness: Option<f64>,
+    pub checkm_contamination: Option<f64>,
+    pub genome_size: Option<i64>,
+    pub gc_percentage: Option<f64>,
+    pub n50_contigs: Option<i64>,
+    pub gtdb_representative: Option<bool>,
+    pub gtdb_genome_representative: Option<String>,
+    pub ncbi_species_taxid: Option<i64>,
+    pub domain: String,
+}
+
+pub fn create_genome_metadata_table(conn: &Connection) -> Result<()> {
+    conn.execute("DROP TABLE IF EXISTS genome_metadata", [])?;
+    conn.execute(
+        "CREATE TABLE genome_metadata (
+            accession TEXT PRIMARY KEY,
+            checkm_completeness REAL,
+            checkm_contamination REAL,
+            genome_size INTEGER,
+            gc_percentage REAL,
+            n50_contigs INTEGER,
+            gtdb_representative INTEGER,
+            gtdb_genome_representative TEXT,
+            ncbi_species_taxid INTEGER,
+            domain TEXT NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX idx_genome_metadata_representative
+        ON genome_metadata (gtdb_genome_representative)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX idx_genome_metadata_domain ON genome_metadata (domain)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX idx_genome_metadata_ncbi_species_taxid
+        ON genome_metadata (ncbi_species_taxid)",
+        [],
+    )?;
+
+    println!("Created table: genome_metadata");
+    Ok(())
+}
+
+/// Inserts genome metadata in batch, replacing existing rows of the same accession.
+pub fn batch_insert_genome_metadata(
+    conn: &mut Connection,
+    genomes: &[GenomeMetadata],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+
+    {
+        let mut stmt = tx.prepare(
+            "
+            INSERT OR REPLACE INTO genome_metadata
+            (accession, checkm_completeness, checkm_contamination, genome_size,
+             gc_percentage, n50_contigs, gtdb_representative, gtdb_genome_representative,
+             ncbi_species_taxid, domain)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+        ",
+        )?;
+
+        for genome in genomes {
+            stmt.execute(params![
+                &genome.accession,
+                genome.checkm_completeness,
+                genome.checkm_contamination,
+                genome.genome_size,
+                genome.gc_percentage,
+                genome.n50_contigs,
+                genome.gtdb_representative,
+                &genome.gtdb_genome_representative,
+                genome.ncbi_species_taxid,
+                &genome.domain,
+            ])?;
+        }
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
 pub fn create_gtdb_tree_tables(conn: &Connection, table_names: &[&str]) -> Result<()> {
     // Drop existing tables
     for table_name in table_names {
@@ -73,8 +163,9 @@ fn create_gtdb_tree_table(conn: &Connection, table_name: &str) -> Result<()> {
             parent INTEGER NOT NULL,
             name TEXT NOT NULL,
             length REAL DEFAULT 0.0,
-            bootstrap REAL DEFAULT 0.0,
-            rank TEXT DEFAULT null
+            bootstrap REAL DEFAULT null,
+            rank TEXT DEFAULT null,
+            red REAL DEFAULT null
         )",
             table_name
         ),
@@ -96,6 +187,36 @@ fn create_gtdb_tree_table(conn: &Connection, table_name: &str) -> Result<()> {
         [],
     )?;
 
+    conn.execute(
+        &format!("DROP TABLE IF EXISTS gtdb_tree_{}_taxa", table_name),
+        [],
+    )?;
+    conn.execute(
+        &format!(
+            "CREATE TABLE gtdb_tree_{}_taxa (
+            node INTEGER NOT NULL,
+            rank TEXT NOT NULL,
+            taxon TEXT NOT NULL
+        )",
+            table_name
+        ),
+        [],
+    )?;
+    conn.execute(
+        &format!(
+            "CREATE INDEX idx_gtdb_tree_{}_taxa_taxon ON gtdb_tree_{}_taxa (taxon)",
+            table_name, table_name
+        ),
+        [],
+    )?;
+    conn.execute(
+        &format!(
+            "CREATE INDEX idx_gtdb_tree_{}_taxa_node ON gtdb_tree_{}_taxa (node)",
+            table_name, table_name
+        ),
+        [],
+    )?;
+
     println!("Created table: gtdb_tree_{}", table_name);
     Ok(())
 }
@@ -137,7 +258,7 @@ pub fn batch_insert_taxonomy(
 pub fn batch_insert_gtdb_tree(
     conn: &mut Connection,
     table_name: &str,
-    trees: &Vec<(usize, usize, String, f64, f64)>,
+    trees: &Vec<(usize, usize, String, f64, Option<f64>)>,
 ) -> Result<()> {
     let tx = conn.transaction()?;
 
@@ -164,6 +285,29 @@ pub fn batch_insert_gtdb_tree(
     Ok(())
 }
 
+/// Inserts the taxa assigned to GTDB tree nodes, one `(node, rank, taxon)` row per rank.
+pub fn batch_insert_gtdb_tree_taxa(
+    conn: &mut Connection,
+    table_name: &str,
+    taxa: &[(usize, String, String)],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+
+    {
+        let query = format!(
+            "INSERT INTO {}_taxa (node, rank, taxon) VALUES (?1, ?2, ?3)",
+            table_name
+        );
+        let mut stmt = tx.prepare(&query)?;
+        for (node, rank, taxon) in taxa {
+            stmt.execute(params![node, rank, taxon])?;
+        }
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
 /// base genome_taxonomy table update gtdb_tree_{} table species
 /// gtdb_tree_{} gta table left join genome_taxonomy gt table on gt.node == gta.name update gta.species = gt.parent
 pub fn update_gtdb_tree_species(conn: &mut Connection, table_name: &str) -> Result<()> {
@@ -192,3 +336,198 @@ pub fn update_gtdb_tree_species(conn: &mut Connection, table_name: &str) -> Resu
     tx.commit()?;
     Ok(())
 }
+
+/// Creates the `gtdb_to_ncbi` and `ncbi_to_gtdb` translation tables.
+pub fn create_translation_tables(conn: &Connection) -> Result<()> {
+    for table_name in ["gtdb_to_ncbi", "ncbi_to_gtdb"] {
+        conn.execute(&format!("DROP TABLE IF EXISTS {}", table_name), [])?;
+        conn.execute(
+            &format!(
+                "CREATE TABLE {} (
+                id INTEGER PRIMARY KEY,
+                rank TEXT NOT NULL,
+                source TEXT NOT NULL,
+                target TEXT NOT NULL,
+                genome_count INTEGER NOT NULL,
+                total_count INTEGER NOT NULL,
+                fraction REAL NOT NULL
+            )",
+                table_name
+            ),
+            [],
+        )?;
+        conn.execute(
+            &format!(
+                "CREATE INDEX idx_{}_source ON {} (source)",
+                table_name, table_name
+            ),
+            [],
+        )?;
+        println!("Created table: {}", table_name);
+    }
+    Ok(())
+}
+
+/// Inserts taxon translations into the specified table in batch.
+///
+/// Each entry is `(rank, source, target, genome_count, total_count)`.
+pub fn batch_insert_translation(
+    conn: &mut Connection,
+    table_name: &str,
+    translations: &[(String, String, String, usize, usize)],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+
+    {
+        let query = format!(
+            "INSERT INTO {} (rank, source, target, genome_count, total_count, fraction)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            table_name
+        );
+        let mut stmt = tx.prepare(&query)?;
+
+        for translation in translations {
+            stmt.execute(params![
+                &translation.0, // rank
+                &translation.1, // source
+                &translation.2, // target
+                translation.3,  // genome_count
+                translation.4,  // total_count
+                translation.3 as f64 / translation.4 as f64,
+            ])?;
+        }
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Name of the temporary table holding the keys of [`query_keys`].
+const INPUT_KEYS_TABLE: &str = "temp.clade_input_keys";
+
+/// Largest number of bound parameters used per query when falling back to chunking.
+/// SQLite builds before 3.32 limit a statement to 999 variables.
+const MAX_KEYS_PER_QUERY: usize = 999;
+
+/// Loads `keys` into a temporary table, replacing its previous content.
+fn load_input_keys(conn: &Connection, keys: &[String]) -> Result<()> {
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY)",
+            INPUT_KEYS_TABLE
+        ),
+        [],
+    )?;
+    conn.execute(&format!("DELETE FROM {}", INPUT_KEYS_TABLE), [])?;
+
+    conn.execute("SAVEPOINT load_input_keys", [])?;
+    if let Err(e) = insert_input_keys(conn, keys) {
+        // Leave no open savepoint, and no partial key list, behind for the fallback
+        conn.execute_batch("ROLLBACK TO load_input_keys; RELEASE load_input_keys")?;
+        return Err(e);
+    }
+    conn.execute("RELEASE load_input_keys", [])?;
+    Ok(())
+}
+
+fn insert_input_keys(conn: &Connection, keys: &[String]) -> Result<()> {
+    let mut stmt = conn.prepare(&format!(
+        "INSERT OR IGNORE INTO {} (key) VALUES (?1)",
+        INPUT_KEYS_TABLE
+    ))?;
+    for key in keys {
+        stmt.execute(params![key])?;
+    }
+    Ok(())
+}
+
+/// Runs a query filtering on an arbitrarily long list of keys.
+///
+/// `query` must contain a `{keys}` marker where the key list goes, as in
+/// `SELECT node FROM genome_taxonomy WHERE node IN ({keys})`. The keys are loaded into a
+/// temporary table which the query selects from, so the number of keys is not bounded by
+/// SQLite's variable limit. If the temporary table cannot be used, the query is run on
+/// chunks of keys with bound parameters instead.
+pub fn query_keys<T, F>(conn: &Connection, query: &str, keys: &[String], mut f: F) -> Result<Vec<T>>
+where
+    F: FnMut(&rusqlite::Row<'_>) -> Result<T>,
+{
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut rows = Vec::new();
+    if load_input_keys(conn, keys).is_ok() {
+        let query = query.replace("{keys}", &format!("SELECT key FROM {}", INPUT_KEYS_TABLE));
+        let mut stmt = conn.prepare(&query)?;
+        let mapped = stmt.query_map([], &mut f)?;
+        for row in mapped {
+            rows.push(row?);
+        }
+        return Ok(rows);
+    }
+
+    for chunk in keys.chunks(MAX_KEYS_PER_QUERY) {
+        let placeholders = vec!["?"; chunk.len()].join(",");
+        let mut stmt = conn.prepare(&query.replace("{keys}", &placeholders))?;
+        let mapped = stmt.query_map(rusqlite::params_from_iter(chunk), &mut f)?;
+        for row in mapped {
+            rows.push(row?);
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database with a table of 3,000 items, `k{i}` of value `i`.
+    fn items() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE items (key TEXT, value INTEGER)", [])
+            .unwrap();
+        for i in 0..3000 {
+            conn.execute(
+                "INSERT INTO items VALUES (?1, ?2)",
+                params![format!("k{}", i), i],
+            )
+            .unwrap();
+        }
+        conn
+    }
+
+    /// Values of every other item, with keys missing from the table in between.
+    fn query_every_other(conn: &Connection) -> Vec<i64> {
+        let keys: Vec<String> = (0..5000).step_by(2).map(|i| format!("k{}", i)).collect();
+        let mut values = query_keys(
+            conn,
+            "SELECT value FROM items WHERE key IN ({keys})",
+            &keys,
+            |row| row.get(0),
+        )
+        .unwrap();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn more_keys_than_sqlite_variables() {
+        let conn = items();
+        let expected: Vec<i64> = (0..3000).step_by(2).collect();
+        assert_eq!(query_every_other(&conn), expected);
+        // The key table is replaced by the next query.
+        assert_eq!(query_every_other(&conn), expected);
+    }
+
+    #[test]
+    fn chunked_fallback_when_the_key_table_is_unusable() {
+        let conn = items();
+        // A table of the same name without a `key` column makes loading the keys fail.
+        conn.execute("CREATE TEMP TABLE clade_input_keys (other TEXT)", [])
+            .unwrap();
+        let expected: Vec<i64> = (0..3000).step_by(2).collect();
+        assert_eq!(query_every_other(&conn), expected);
+        assert!(conn.is_autocommit());
+    }
+}
@@ -0,0 +1,640 @@
+use crate::generate::{filter_genomes, process_data, InputOptions, Tip};
+use crate::input::{AbundanceTable, InputRecord};
+use crate::tree::GtdbTree;
+use rusqlite::Connection;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// Faith's phylogenetic diversity of a set of tree positions.
+///
+/// The sum of the branch lengths of the subtree spanning the positions. When `rooted`,
+/// the path to the root of the tree is included, as in picante's `pd`; otherwise the
+/// subtree stops at the lowest common ancestor of the positions.
+pub fn faith_pd(tree: &GtdbTree, positions: &[usize], rooted: bool) -> f64 {
+    let mut visited = vec![false; tree.len()];
+    if !rooted {
+        // Branches above the LCA are not part of the subtree.
+        let mut current = tree.lca(positions);
+        while let Some(pos) = current {
+            visited[pos] = true;
+            current = tree.parents[pos];
+        }
+    }
+
+    let mut pd = 0.0;
+    for &pos in positions {
+        let mut current = pos;
+        while !visited[current] {
+            visited[current] = true;
+            match tree.parents[current] {
+                Some(parent) => {
+                    pd += tree.nodes[current].length;
+                    current = parent;
+                }
+                None => break,
+            }
+        }
+    }
+    pd
+}
+
+/// Phylogenetic diversity of one genome set within one domain tree.
+#[derive(Debug, Clone)]
+pub struct SetPd {
+    pub set: String,
+    pub domain: String,
+    pub genome_count: usize,
+    pub pd: f64,
+}
+
+/// Computes Faith's PD of each genome set, one row per set and domain.
+///
+/// Tips must be keyed by representative accession, the tip names of the GTDB trees.
+/// Each domain tree is loaded once, whatever the number of sets.
+pub fn sets_pd(
+    db: &PathBuf,
+    sets: &BTreeMap<String, Vec<Tip>>,
+    rooted: bool,
+) -> Result<Vec<SetPd>, Box<dyn std::error::Error>> {
+    let conn = Connection::open(db)?;
+    let mut trees: HashMap<String, (GtdbTree, HashMap<String, usize>)> = HashMap::new();
+
+    let mut results = Vec::new();
+    for (set, tips) in sets {
+        let mut by_domain: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for tip in tips {
+            by_domain.entry(&tip.domain).or_default().push(&tip.key);
+        }
+        for (domain, keys) in by_domain {
+            if !trees.contains_key(domain) {
+                let tree = GtdbTree::load(&conn, &format!("gtdb_tree_{}", domain))?;
+                let leaves = (0..tree.len())
+                    .filter(|&pos| tree.children[pos].is_empty())
+                    .map(|pos| (tree.nodes[pos].name.clone(), pos))
+                    .collect();
+                trees.insert(domain.to_string(), (tree, leaves));
+            }
+            let (tree, leaves) = &trees[domain];
+            let mut positions: Vec<usize> = keys
+                .iter()
+                .filter_map(|key| leaves.get(*key).copied())
+                .collect();
+            positions.sort_unstable();
+            positions.dedup();
+            results.push(SetPd {
+                set: set.clone(),
+                domain: domain.to_string(),
+                genome_count: positions.len(),
+                pd: faith_pd(tree, &positions, rooted),
+            });
+        }
+    }
+    Ok(results)
+}
+
+/// Output formats of distance matrices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MatrixFormat {
+    /// Square PHYLIP matrix.
+    Phylip,
+    /// Long format, one `a<TAB>b<TAB>distance` row per pair.
+    Tsv,
+}
+
+/// A symmetric distance matrix between named items.
+#[derive(Debug, Clone)]
+pub struct DistanceMatrix {
+    pub names: Vec<String>,
+    pub distances: Vec<Vec<f64>>,
+}
+
+impl DistanceMatrix {
+    /// Tip-to-tip branch-length distances between rows placed at leaves of the tree, given
+    /// as `(row, leaf name)` pairs.
+    ///
+    /// The distance of two leaves is the sum of their distances to their lowest common
+    /// ancestor. Several rows may share a leaf, e.g. genomes of one species at the tip of
+    /// their representative, at distance zero. Rows whose leaf is not found and repeated
+    /// rows are left out.
+    pub fn patristic(tree: &GtdbTree, rows: &[(String, String)]) -> Self {
+        let leaves: HashMap<&str, usize> = (0..tree.len())
+            .filter(|&pos| tree.children[pos].is_empty())
+            .map(|pos| (tree.nodes[pos].name.as_str(), pos))
+            .collect();
+        let mut seen = HashSet::new();
+        let found: Vec<(String, usize)> = rows
+            .iter()
+            .filter_map(|(row, leaf)| Some((row, *leaves.get(leaf.as_str())?)))
+            .filter(|(row, _)| seen.insert(row.as_str()))
+            .map(|(row, pos)| (row.clone(), pos))
+            .collect();
+
+        let root_distances = root_distances(tree);
+        // Ancestors of the current row leaf are stamped with its index, so the LCA of a
+        // pair is the first stamped node on the way up from the other leaf.
+        let mut stamps = vec![usize::MAX; tree.len()];
+        let mut distances = vec![vec![0.0; found.len()]; found.len()];
+        for (i, &(_, a)) in found.iter().enumerate() {
+            let mut current = Some(a);
+            while let Some(pos) = current {
+                stamps[pos] = i;
+                current = tree.parents[pos];
+            }
+            for j in 0..i {
+                let b = found[j].1;
+                let mut lca = b;
+                while stamps[lca] != i {
+                    match tree.parents[lca] {
+                        Some(parent) => lca = parent,
+                        None => break,
+                    }
+                }
+                let distance = root_distances[a] + root_distances[b] - 2.0 * root_distances[lca];
+                distances[i][j] = distance;
+                distances[j][i] = distance;
+            }
+        }
+
+        DistanceMatrix {
+            names: found.into_iter().map(|(name, _)| name).collect(),
+            distances,
+        }
+    }
+
+    pub fn to_phylip(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{}", self.names.len()).unwrap();
+        for (name, row) in self.names.iter().zip(&self.distances) {
+            out.push_str(name);
+            for distance in row {
+                write!(out, " {:.6}", distance).unwrap();
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn to_tsv(&self) -> String {
+        let mut out = String::from("a\tb\tdistance\n");
+        for i in 0..self.names.len() {
+            for j in i + 1..self.names.len() {
+                writeln!(
+                    out,
+                    "{}\t{}\t{:.6}",
+                    self.names[i], self.names[j], self.distances[i][j]
+                )
+                .unwrap();
+            }
+        }
+        out
+    }
+}
+
+/// Distance from the root of the tree to every node, by position.
+pub fn root_distances(tree: &GtdbTree) -> Vec<f64> {
+    let mut distances = vec![0.0; tree.len()];
+    for pos in tree.preorder(tree.root) {
+        if let Some(parent) = tree.parents[pos] {
+            distances[pos] = distances[parent] + tree.nodes[pos].length;
+        }
+    }
+    distances
+}
+
+/// UniFrac distances between samples with the given leaf abundances.
+///
+/// Unweighted UniFrac is the fraction of branch length leading to leaves of only one of
+/// the two samples. Weighted UniFrac is the normalized form of Lozupone et al. (2007),
+/// over relative abundances. The tree is restricted to the clade spanning all observed
+/// leaves, so branches above their common ancestor are ignored, as on a pruned tree.
+pub fn unifrac(
+    tree: &GtdbTree,
+    samples: &[String],
+    abundances: &[HashMap<usize, f64>],
+    weighted: bool,
+) -> DistanceMatrix {
+    let mut observed: Vec<usize> = abundances
+        .iter()
+        .flat_map(|sample| sample.iter().filter(|(_, &a)| a > 0.0).map(|(&pos, _)| pos))
+        .collect();
+    observed.sort_unstable();
+    observed.dedup();
+
+    // Nodes spanning the observed leaves below their LCA, children before parents.
+    let mut relevant = vec![false; tree.len()];
+    let mut order = Vec::new();
+    if let Some(lca) = tree.lca(&observed) {
+        for &pos in &observed {
+            let mut current = pos;
+            while current != lca && !relevant[current] {
+                relevant[current] = true;
+                current = tree.parents[current].unwrap_or(lca);
+            }
+        }
+        order = tree
+            .preorder(lca)
+            .into_iter()
+            .filter(|&pos| relevant[pos])
+            .rev()
+            .collect();
+    }
+    let index: HashMap<usize, usize> = order.iter().enumerate().map(|(k, &pos)| (pos, k)).collect();
+    let parents: Vec<Option<usize>> = order
+        .iter()
+        .map(|&pos| tree.parents[pos].and_then(|parent| index.get(&parent).copied()))
+        .collect();
+    let lengths: Vec<f64> = order.iter().map(|&pos| tree.nodes[pos].length).collect();
+
+    // Relative abundance below each branch, per sample.
+    let branch_abundances: Vec<Vec<f64>> = abundances
+        .iter()
+        .map(|sample| {
+            let total: f64 = sample.values().sum();
+            let mut below = vec![0.0; order.len()];
+            for (pos, &abundance) in sample {
+                if let Some(&k) = index.get(pos) {
+                    below[k] += if total > 0.0 { abundance / total } else { 0.0 };
+                }
+            }
+            for k in 0..order.len() {
+                if let Some(parent) = parents[k] {
+                    below[parent] += below[k];
+                }
+            }
+            below
+        })
+        .collect();
+
+    // Distance from the LCA to each leaf, for the weighted normalization.
+    let mut depths = vec![0.0; order.len()];
+    for k in (0..order.len()).rev() {
+        depths[k] = lengths[k] + parents[k].map_or(0.0, |parent| depths[parent]);
+    }
+    let is_leaf: Vec<bool> = order
+        .iter()
+        .map(|&pos| tree.children[pos].is_empty())
+        .collect();
+
+    let n = samples.len();
+    let mut distances = vec![vec![0.0; n]; n];
+    for i in 0..n {
+        for j in 0..i {
+            let (a, b) = (&branch_abundances[i], &branch_abundances[j]);
+            let (mut numerator, mut denominator) = (0.0, 0.0);
+            for k in 0..order.len() {
+                if weighted {
+                    numerator += lengths[k] * (a[k] - b[k]).abs();
+                    if is_leaf[k] {
+                        denominator += depths[k] * (a[k] + b[k]);
+                    }
+                } else {
+                    let (in_a, in_b) = (a[k] > 0.0, b[k] > 0.0);
+                    if in_a != in_b {
+                        numerator += lengths[k];
+                    }
+                    if in_a || in_b {
+                        denominator += lengths[k];
+                    }
+                }
+            }
+            let distance = if denominator > 0.0 {
+                numerator / denominator
+            } else {
+                0.0
+            };
+            distances[i][j] = distance;
+            distances[j][i] = distance;
+        }
+    }
+
+    DistanceMatrix {
+        names: samples.to_vec(),
+        distances,
+    }
+}
+
+/// Computes UniFrac between the samples of an abundance table on the GTDB tree.
+///
+/// Items are resolved like `gtdb newick` inputs, to species representatives; items
+/// resolving to several genomes, such as genera, are unresolved. All items must belong to
+/// one domain, or `domain` selects the tree and items of other domains are ignored.
+pub fn table_unifrac(
+    db: &PathBuf,
+    table: &AbundanceTable,
+    domain: Option<&str>,
+    weighted: bool,
+    skip_missing: bool,
+) -> Result<DistanceMatrix, Box<dyn std::error::Error>> {
+    let options = InputOptions {
+        representatives_only: true,
+        skip_missing,
+        ..Default::default()
+    };
+    let records = table
+        .items
+        .iter()
+        .map(|item| InputRecord::new(item, None))
+        .collect();
+    let mut resolved = process_data(records, db, &options)?;
+    for r in resolved.report.iter_mut() {
+        if r.resolved.len() > 1 {
+            r.error = Some(format!("resolves to {} genomes", r.resolved.len()));
+        }
+    }
+    if skip_missing {
+        for r in resolved.failed() {
+            eprintln!(
+                "Skipped {}: {}",
+                r.input,
+                r.error.as_deref().unwrap_or_default()
+            );
+        }
+    } else {
+        resolved.ensure_complete()?;
+    }
+
+    let domains: HashMap<&str, &str> = resolved
+        .tips
+        .iter()
+        .map(|tip| (tip.key.as_str(), tip.domain.as_str()))
+        .collect();
+    let domain = match domain {
+        Some(domain) => domain.to_string(),
+        None => {
+            let mut found: Vec<&str> = domains.values().copied().collect();
+            found.sort();
+            found.dedup();
+            match found.as_slice() {
+                [domain] => domain.to_string(),
+                [] => return Err("No item of the table resolved".into()),
+                _ => {
+                    return Err(format!(
+                        "Items span several domains, choose one with --domain: {}",
+                        found.join(", ")
+                    )
+                    .into())
+                }
+            }
+        }
+    };
+
+    let conn = Connection::open(db)?;
+    let tree = GtdbTree::load(&conn, &format!("gtdb_tree_{}", domain))?;
+    let leaves: HashMap<&str, usize> = (0..tree.len())
+        .filter(|&pos| tree.children[pos].is_empty())
+        .map(|pos| (tree.nodes[pos].name.as_str(), pos))
+        .collect();
+
+    let mut abundances = vec![HashMap::new(); table.samples.len()];
+    for (r, row) in resolved.report.iter().zip(&table.abundances) {
+        let [key] = r.resolved.as_slice() else {
+            continue;
+        };
+        if r.error.is_some() || domains.get(key.as_str()) != Some(&domain.as_str()) {
+            continue;
+        }
+        let Some(&pos) = leaves.get(key.as_str()) else {
+            continue;
+        };
+        for (sample, &abundance) in abundances.iter_mut().zip(row) {
+            *sample.entry(pos).or_insert(0.0) += abundance;
+        }
+    }
+    Ok(unifrac(&tree, &table.samples, &abundances, weighted))
+}
+
+/// Branch-length distance from a node to every node of the tree, by position.
+pub fn distances_from(tree: &GtdbTree, start: usize) -> Vec<f64> {
+    let mut distances = vec![f64::INFINITY; tree.len()];
+    distances[start] = 0.0;
+    let mut stack = vec![start];
+    while let Some(pos) = stack.pop() {
+        let mut visit = |next: usize, length: f64| {
+            if distances[next].is_infinite() {
+                distances[next] = distances[pos] + length;
+                stack.push(next);
+            }
+        };
+        if let Some(parent) = tree.parents[pos] {
+            visit(parent, tree.nodes[pos].length);
+        }
+        for &child in &tree.children[pos] {
+            visit(child, tree.nodes[child].length);
+        }
+    }
+    distances
+}
+
+/// A genome close to a query genome on the reference tree.
+#[derive(Debug, Clone)]
+pub struct Neighbor {
+    pub accession: String,
+    pub species: String,
+    pub distance: f64,
+    pub representative: bool,
+}
+
+/// Finds the `k` genomes nearest to a query genome by patristic distance.
+///
+/// Genomes that are not species representatives are placed at the tree tip of their
+/// representative. Candidates can be restricted to representatives and to the genomes
+/// passing a `genome_metadata` filter. Ties are ordered by accession.
+pub fn nearest_genomes(
+    db: &PathBuf,
+    query: &str,
+    k: usize,
+    representatives_only: bool,
+    filter: Option<&str>,
+) -> Result<Vec<Neighbor>, Box<dyn std::error::Error>> {
+    let options = InputOptions {
+        genome_tips: true,
+        ..Default::default()
+    };
+    let resolved = process_data(vec![InputRecord::new(query, None)], db, &options)?;
+    resolved.ensure_complete()?;
+    let [tip] = resolved.tips.as_slice() else {
+        return Err(format!("{} does not resolve to a single genome", query).into());
+    };
+
+    let conn = Connection::open(db)?;
+    let tree = GtdbTree::load(&conn, &format!("gtdb_tree_{}", tip.domain))?;
+    let leaves: HashMap<&str, usize> = (0..tree.len())
+        .filter(|&pos| tree.children[pos].is_empty())
+        .map(|pos| (tree.nodes[pos].name.as_str(), pos))
+        .collect();
+    let start = *leaves
+        .get(tip.key.as_str())
+        .ok_or_else(|| format!("The representative of {} is not in the tree", query))?;
+    let distances = distances_from(&tree, start);
+
+    let mut stmt = conn.prepare(
+        "SELECT accession, COALESCE(gtdb_genome_representative, accession)
+        FROM genome_metadata WHERE domain = ?1",
+    )?;
+    let genomes = stmt
+        .query_map([&tip.domain], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+    let passing = filter
+        .map(|filter| filter_genomes(&conn, filter))
+        .transpose()?;
+
+    let own = tip.genome.as_deref().unwrap_or(&tip.key);
+    let mut candidates: Vec<(f64, String, bool)> = genomes
+        .into_iter()
+        .filter(|(accession, representative)| {
+            accession != own
+                && (!representatives_only || accession == representative)
+                && passing.as_ref().is_none_or(|p| p.contains(accession))
+        })
+        .filter_map(|(accession, representative)| {
+            let pos = leaves.get(representative.as_str())?;
+            let is_representative = accession == representative;
+            Some((distances[*pos], accession, is_representative))
+        })
+        .collect();
+    candidates.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+    candidates.truncate(k);
+
+    let accessions: Vec<String> = candidates.iter().map(|c| c.1.clone()).collect();
+    let species: HashMap<String, String> = crate::db::query_keys(
+        &conn,
+        "SELECT node, parent FROM genome_taxonomy WHERE node IN ({keys})",
+        &accessions,
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+    )?
+    .into_iter()
+    .collect();
+
+    Ok(candidates
+        .into_iter()
+        .map(|(distance, accession, representative)| Neighbor {
+            species: species.get(&accession).cloned().unwrap_or_default(),
+            accession,
+            distance,
+            representative,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::tests::example_tree;
+
+    const A: usize = 2;
+    const B: usize = 3;
+    const C: usize = 5;
+    const D: usize = 6;
+
+    #[test]
+    fn faith_pd_rooted_and_unrooted() {
+        let tree = example_tree();
+        assert_eq!(faith_pd(&tree, &[A, C], true), 4.0);
+        assert_eq!(faith_pd(&tree, &[A, B], true), 3.0);
+        assert_eq!(faith_pd(&tree, &[A, B], false), 2.0);
+        assert_eq!(faith_pd(&tree, &[D], false), 0.0);
+    }
+
+    #[test]
+    fn patristic_rows_sharing_a_leaf_are_at_zero() {
+        let tree = example_tree();
+        let rows: Vec<(String, String)> = [("a1", "A"), ("a2", "A"), ("d", "D"), ("a1", "A")]
+            .iter()
+            .chain(&[("x", "missing")])
+            .map(|(row, leaf)| (row.to_string(), leaf.to_string()))
+            .collect();
+        let matrix = DistanceMatrix::patristic(&tree, &rows);
+        assert_eq!(matrix.names, ["a1", "a2", "d"]);
+        assert_eq!(
+            matrix.distances,
+            [[0.0, 0.0, 6.0], [0.0, 0.0, 6.0], [6.0, 6.0, 0.0]]
+        );
+    }
+
+    fn samples(abundances: &[&[(usize, f64)]]) -> Vec<HashMap<usize, f64>> {
+        abundances
+            .iter()
+            .map(|sample| sample.iter().copied().collect())
+            .collect()
+    }
+
+    #[test]
+    fn unweighted_unifrac_is_the_unique_branch_fraction() {
+        let tree = example_tree();
+        let names = vec!["s1".to_string(), "s2".to_string(), "s3".to_string()];
+        let abundances = samples(&[
+            &[(A, 1.0), (C, 1.0)],
+            &[(A, 2.0), (D, 1.0)],
+            &[(A, 5.0), (C, 1.0)],
+        ]);
+        let matrix = unifrac(&tree, &names, &abundances, false);
+        // C and D are unique, out of A, X, C, Y and D.
+        assert!((matrix.distances[0][1] - 4.0 / 7.0).abs() < 1e-12);
+        assert_eq!(matrix.distances[0][2], 0.0);
+        assert_eq!(matrix.distances[1][0], matrix.distances[0][1]);
+    }
+
+    #[test]
+    fn weighted_unifrac_of_disjoint_sister_tips() {
+        let tree = example_tree();
+        let names = vec!["s1".to_string(), "s2".to_string()];
+        let abundances = samples(&[&[(A, 3.0)], &[(B, 1.0)]]);
+        let matrix = unifrac(&tree, &names, &abundances, true);
+        assert!((matrix.distances[0][1] - 1.0).abs() < 1e-12);
+    }
+
+    /// Neighbors of RS_GCF_000011.1 as (accession, distance rounded to 1e-6, representative).
+    fn neighbors(
+        db: &PathBuf,
+        k: usize,
+        representatives_only: bool,
+        filter: Option<&str>,
+    ) -> Vec<(String, f64, bool)> {
+        nearest_genomes(db, "RS_GCF_000011.1", k, representatives_only, filter)
+            .unwrap()
+            .into_iter()
+            .map(|n| {
+                (
+                    n.accession,
+                    (n.distance * 1e6).round() / 1e6,
+                    n.representative,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nearest_genomes_of_a_non_representative() {
+        // RS_GCF_000011.1 and RS_GCF_000012.1 sit at the tip of their representative
+        // RS_GCF_000001.1, RS_GCF_000013.1 at the tip of GB_GCA_000002.1.
+        let db = crate::gtdb::tests::release_db("nearest-genomes");
+        let accessions = |neighbors: Vec<(String, f64, bool)>| -> Vec<String> {
+            neighbors
+                .into_iter()
+                .map(|(accession, _, _)| accession)
+                .collect()
+        };
+        assert_eq!(
+            neighbors(&db, 4, false, None),
+            [
+                ("RS_GCF_000001.1".to_string(), 0.0, true),
+                ("RS_GCF_000012.1".to_string(), 0.0, false),
+                ("GB_GCA_000002.1".to_string(), 0.3, true),
+                ("RS_GCF_000013.1".to_string(), 0.3, false),
+            ]
+        );
+        assert_eq!(
+            accessions(neighbors(&db, 3, true, None)),
+            ["RS_GCF_000001.1", "GB_GCA_000002.1", "RS_GCF_000005.1"]
+        );
+        assert_eq!(
+            accessions(neighbors(&db, 3, false, Some("checkm_contamination < 2"))),
+            ["RS_GCF_000001.1", "RS_GCF_000013.1", "RS_GCF_000003.1"]
+        );
+        assert!(nearest_genomes(&db, "RS_GCF_999999.1", 3, false, None).is_err());
+        std::fs::remove_dir_all(db.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+use std::io;
+
+/// Columns of `genome_metadata` usable in filter expressions, with their accepted aliases.
+const FILTER_COLUMNS: [(&str, &[&str]); 6] = [
+    ("checkm_completeness", &["completeness"]),
+    ("checkm_contamination", &["contamination"]),
+    ("genome_size", &["size"]),
+    ("gc_percentage", &["gc"]),
+    ("n50_contigs", &["n50"]),
+    ("gtdb_representative", &["representative"]),
+];
+
+const FILTER_OPERATORS: [&str; 7] = [">=", "<=", "!=", "==", ">", "<", "="];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    Op(String),
+    LParen,
+    RParen,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn tokenize(expr: &str) -> io::Result<Vec<Token>> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+        } else if c == '(' {
+            tokens.push(Token::LParen);
+            pos += 1;
+        } else if c == ')' {
+            tokens.push(Token::RParen);
+            pos += 1;
+        } else if let Some(op) = FILTER_OPERATORS
+            .iter()
+            .find(|op| chars[pos..].iter().take(op.len()).copied().eq(op.chars()))
+        {
+            tokens.push(Token::Op(op.to_string()));
+            pos += op.len();
+        } else if c.is_ascii_digit() || c == '.' || c == '-' {
+            let start = pos;
+            pos += 1;
+            while pos < chars.len()
+                && (chars[pos].is_ascii_alphanumeric() || chars[pos] == '.' || chars[pos] == '-')
+            {
+                pos += 1;
+            }
+            let number: String = chars[start..pos].iter().collect();
+            let value = number
+                .parse::<f64>()
+                .map_err(|_| invalid(format!("Invalid number in filter: {}", number)))?;
+            tokens.push(Token::Number(value));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = pos;
+            while pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || chars[pos] == '_') {
+                pos += 1;
+            }
+            tokens.push(Token::Ident(chars[start..pos].iter().collect()));
+        } else {
+            return Err(invalid(format!("Unexpected character in filter: {}", c)));
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    sql: String,
+    values: Vec<f64>,
+}
+
+impl Parser {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.tokens.get(self.pos), Some(Token::Ident(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn expr(&mut self) -> io::Result<()> {
+        self.term()?;
+        while self.peek_keyword("or") {
+            self.pos += 1;
+            self.sql.push_str(" OR ");
+            self.term()?;
+        }
+        Ok(())
+    }
+
+    fn term(&mut self) -> io::Result<()> {
+        self.factor()?;
+        while self.peek_keyword("and") {
+            self.pos += 1;
+            self.sql.push_str(" AND ");
+            self.factor()?;
+        }
+        Ok(())
+    }
+
+    fn factor(&mut self) -> io::Result<()> {
+        if self.peek_keyword("not") {
+            self.pos += 1;
+            self.sql.push_str("NOT ");
+            return self.factor();
+        }
+        match self.tokens.get(self.pos).cloned() {
+            Some(Token::LParen) => {
+                self.pos += 1;
+                self.sql.push('(');
+                self.expr()?;
+                if self.tokens.get(self.pos) != Some(&Token::RParen) {
+                    return Err(invalid("Missing closing parenthesis in filter".into()));
+                }
+                self.pos += 1;
+                self.sql.push(')');
+                Ok(())
+            }
+            Some(Token::Ident(name)) => {
+                let column = FILTER_COLUMNS
+                    .iter()
+                    .find(|(column, aliases)| {
+                        column.eq_ignore_ascii_case(&name)
+                            || aliases.iter().any(|a| a.eq_ignore_ascii_case(&name))
+                    })
+                    .map(|(column, _)| *column)
+                    .ok_or_else(|| invalid(format!("Unknown filter column: {}", name)))?;
+                let op = match self.tokens.get(self.pos + 1) {
+                    Some(Token::Op(op)) if op == "==" => "=".to_string(),
+                    Some(Token::Op(op)) => op.clone(),
+                    _ => return Err(invalid(format!("Expected an operator after {}", name))),
+                };
+                let value = match self.tokens.get(self.pos + 2) {
+                    Some(Token::Number(value)) => *value,
+                    Some(Token::Ident(word)) if ["true", "t"].contains(&word.as_str()) => 1.0,
+                    Some(Token::Ident(word)) if ["false", "f"].contains(&word.as_str()) => 0.0,
+                    _ => return Err(invalid(format!("Expected a value after {} {}", name, op))),
+                };
+                self.pos += 3;
+                self.values.push(value);
+                self.sql
+                    .push_str(&format!("{} {} ?{}", column, op, self.values.len()));
+                Ok(())
+            }
+            _ => Err(invalid("Expected a condition in filter".into())),
+        }
+    }
+}
+
+/// Parses a genome filter such as `checkm_completeness>95 AND contamination<5`.
+///
+/// Returns a SQL condition over `genome_metadata` with numbered placeholders, and the
+/// values to bind to them. Only known metadata columns are accepted, so the condition is
+/// safe to embed in a query.
+pub fn parse_filter(expr: &str) -> io::Result<(String, Vec<f64>)> {
+    let mut parser = Parser {
+        tokens: tokenize(expr)?,
+        pos: 0,
+        sql: String::new(),
+        values: Vec::new(),
+    };
+    parser.expr()?;
+    if parser.pos != parser.tokens.len() {
+        return Err(invalid(format!(
+            "Unexpected trailing input in filter: {}",
+            expr
+        )));
+    }
+    Ok((parser.sql, parser.values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conditions_use_columns_and_placeholders() {
+        let (sql, values) = parse_filter("checkm_completeness>95 AND contamination<5").unwrap();
+        assert_eq!(
+            sql,
+            "checkm_completeness > ?1 AND checkm_contamination < ?2"
+        );
+        assert_eq!(values, [95.0, 5.0]);
+    }
+
+    #[test]
+    fn keywords_parentheses_and_booleans() {
+        let (sql, values) = parse_filter("not (gc >= 50.5 or representative == true)").unwrap();
+        assert_eq!(sql, "NOT (gc_percentage >= ?1 OR gtdb_representative = ?2)");
+        assert_eq!(values, [50.5, 1.0]);
+
+        let (sql, values) = parse_filter("size<=1e6").unwrap();
+        assert_eq!(sql, "genome_size <= ?1");
+        assert_eq!(values, [1e6]);
+    }
+
+    #[test]
+    fn invalid_filters_are_rejected() {
+        for filter in [
+            "accession = 1",
+            "completeness >",
+            "(gc > 50",
+            "gc > 50 gc",
+            "gc > 50; DROP TABLE genome_metadata",
+            "gc > abc",
+            "",
+        ] {
+            assert!(parse_filter(filter).is_err(), "{}", filter);
+        }
+    }
+}
@@ -1,167 +1,1029 @@
-use rusqlite::{params_from_iter, Connection, Result};
+use crate::input::InputRecord;
+use rusqlite::{params_from_iter, Connection, OptionalExtension, Result};
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 
-pub fn generate_newick_tree(db: &PathBuf, input_data: Vec<String>, domain: &str) -> Result<String> {
-    let conn = Connection::open(db).expect("Failed to open database");
+/// Options controlling how inputs are resolved to tree tips.
+#[derive(Debug, Clone, Default)]
+pub struct InputOptions {
+    /// Filter over `genome_metadata` applied to the genomes of all inputs, see
+    /// [`crate::filter::parse_filter`].
+    pub filter: Option<String>,
+    /// Resolve every input to its species representative genome, one tip per species.
+    pub representatives_only: bool,
+    /// Build the tree from the inputs that resolve instead of failing on missing ones.
+    pub skip_missing: bool,
+    /// Keep every input genome as its own tip instead of collapsing to its species.
+    pub genome_tips: bool,
+    /// Label genome tips with their species and accession, so labels are unique.
+    pub unique_labels: bool,
+}
+
+impl InputOptions {
+    /// Whether tips are matched by representative accession rather than species name.
+    fn by_representative(&self) -> bool {
+        self.representatives_only || self.genome_tips
+    }
+}
+
+/// Options controlling how the pruned trees are transformed before output.
+#[derive(Debug, Clone, Default)]
+pub struct TreeOptions {
+    /// Collapse the tree to this rank, by name (`genus`) or prefix (`g__`).
+    pub collapse_to: Option<String>,
+    /// Reduce each domain tree to a diverse subset of its tips.
+    pub subsample: Option<crate::subsample::Subsample>,
+    /// Root each domain tree, see [`crate::rooting::Rooting`].
+    pub root: Option<crate::rooting::Rooting>,
+    /// Order of the children of each node.
+    pub order: Option<crate::tree::ChildOrder>,
+    /// Resolve polytomies into bifurcations.
+    pub resolve_polytomies: Option<crate::tree::PolytomyResolution>,
+    /// Seed of random polytomy resolution.
+    pub seed: u64,
+    /// Collapse internal branches with a lower support into polytomies.
+    pub min_support: Option<f64>,
+    /// Collapse internal branches shorter than this into polytomies.
+    pub min_length: Option<f64>,
+}
+
+/// A resolved input, to be matched against the tree of its domain.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Tip {
+    /// Species name, or representative accession with `representatives_only` or `genome_tips`.
+    pub key: String,
+    pub domain: String,
+    /// The genome itself with `genome_tips`, possibly not the representative `key`.
+    pub genome: Option<String>,
+    /// Custom tip label given with the input.
+    pub label: Option<String>,
+}
+
+/// Builds one pruned tree per domain the resolved tips belong to.
+///
+/// When `domain` is given, only tips of that domain are kept. Trees are returned in
+/// domain order.
+pub fn build_domain_trees(
+    db: &PathBuf,
+    tips: &[Tip],
+    domain: Option<&str>,
+    options: &InputOptions,
+    tree_options: &TreeOptions,
+) -> Result<Vec<(String, crate::tree::Node)>, Box<dyn std::error::Error>> {
+    let conn = Connection::open(db)?;
+
+    let mut domains: Vec<&str> = tips.iter().map(|tip| tip.domain.as_str()).collect();
+    domains.sort();
+    domains.dedup();
+    if let Some(domain) = domain {
+        let skipped = tips.iter().filter(|tip| tip.domain != domain).count();
+        if skipped > 0 {
+            eprintln!("Skipping {} inputs outside of domain {}", skipped, domain);
+        }
+        domains.retain(|d| *d == domain);
+    }
+
+    let mut trees = Vec::new();
+    for domain in domains {
+        let keys: Vec<String> = tips
+            .iter()
+            .filter(|tip| tip.domain == domain)
+            .map(|tip| tip.key.clone())
+            .collect();
+        let table_name = format!("gtdb_tree_{domain}");
+        let mut leaf_nodes = if options.by_representative() {
+            crate::tree::get_leaf_nodes_by_name(&conn, &table_name, &keys)?
+        } else {
+            crate::tree::get_leaf_nodes_by_rank(&conn, &table_name, &keys)?
+        };
+        let tree = crate::tree::GtdbTree::load(&conn, &table_name)?;
 
-    let ranks = process_data(input_data, db).expect("Failed to process data");
+        let mut subsample = tree_options.subsample.clone();
+        let mut outgroup = None;
+        if tree_options.root == Some(crate::rooting::Rooting::Outgroup) {
+            let positions: Vec<usize> = leaf_nodes
+                .iter()
+                .filter_map(|node| tree.index.get(node).copied())
+                .collect();
+            if let Some(pos) = suggest_outgroup(&tree, &positions) {
+                let node = &tree.nodes[pos];
+                eprintln!(
+                    "Outgroup for {}: {} ({})",
+                    domain,
+                    node.name,
+                    node.rank.as_deref().unwrap_or_default()
+                );
+                leaf_nodes.push(node.node);
+                outgroup = Some(node.node);
+                if let Some(subsample) = subsample.as_mut() {
+                    subsample.keep.insert(node.name.clone());
+                }
+            } else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("No outgroup for {}, the inputs span the whole tree", domain),
+                )
+                .into());
+            }
+        }
+
+        if let Some(mut root) = crate::tree::build_pruned_tree(&tree, &leaf_nodes) {
+            if options.genome_tips {
+                attach_genomes(&mut root, tips, domain, options.unique_labels);
+            } else {
+                label_input_tips(&mut root, tips, domain, options);
+            }
+            crate::tree::collapse_weak_branches(
+                &mut root,
+                tree_options.min_support,
+                tree_options.min_length,
+            );
+            if let Some(subsample) = &subsample {
+                crate::subsample::subsample_tree(&conn, &mut root, subsample)?;
+            }
+            if let Some(rooting) = &tree_options.root {
+                root = root_tree(&conn, root, rooting, outgroup)?;
+            }
+            if let Some(rank) = &tree_options.collapse_to {
+                collapse_to_rank(&conn, &mut root, rank)?;
+            }
+            if let Some(resolution) = tree_options.resolve_polytomies {
+                crate::tree::resolve_polytomies(&mut root, resolution, tree_options.seed);
+            }
+            if let Some(order) = tree_options.order {
+                crate::tree::order_children(&mut root, order, &|node| node.node as u64);
+            }
+            trees.push((domain.to_string(), root));
+        }
+    }
+    Ok(trees)
+}
+
+/// Labels the tips of the pruned tree with the custom labels given with the inputs.
+fn label_input_tips(
+    root: &mut crate::tree::Node,
+    tips: &[Tip],
+    domain: &str,
+    options: &InputOptions,
+) {
+    let labels: HashMap<&str, &str> = tips
+        .iter()
+        .filter(|tip| tip.domain == domain)
+        .filter_map(|tip| Some((tip.key.as_str(), tip.label.as_deref()?)))
+        .collect();
+    if !labels.is_empty() {
+        crate::tree::label_tips(root, |node| {
+            let key = if options.representatives_only {
+                Some(node.name.as_str())
+            } else {
+                node.rank.as_deref()
+            };
+            key.and_then(|key| labels.get(key))
+                .map(|label| label.to_string())
+        });
+    }
+}
+
+/// Collapses the tree to one tip per taxon of `rank`, see [`crate::tree::collapse_groups`].
+///
+/// Tips are assigned to taxa through the lineage of their species in `genome_taxonomy`.
+fn collapse_to_rank(
+    conn: &Connection,
+    root: &mut crate::tree::Node,
+    rank: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let prefix = crate::stats::gtdb_rank_prefix(rank)
+        .ok_or_else(|| std::io::Error::other(format!("Unknown rank: {}", rank)))?;
+
+    let lineages = species_lineages(conn, root)?;
+    let taxa: HashMap<String, String> = lineages
+        .into_iter()
+        .filter_map(|(species, lineage)| {
+            let taxon = if prefix == "s__" {
+                species.clone()
+            } else {
+                crate::translate::taxonomy_ranks(&lineage)
+                    .into_iter()
+                    .find(|(r, _)| *r == prefix)?
+                    .1
+                    .to_string()
+            };
+            Some((species, taxon))
+        })
+        .collect();
+
+    crate::tree::collapse_groups(root, |node| {
+        node.rank
+            .as_ref()
+            .and_then(|species| taxa.get(species))
+            .cloned()
+    });
+    Ok(())
+}
+
+/// Returns the lineage (`ancestor_sequence`) of the species of every tip, by species.
+fn species_lineages(
+    conn: &Connection,
+    root: &crate::tree::Node,
+) -> rusqlite::Result<HashMap<String, String>> {
+    let mut species = Vec::new();
+    let mut stack = vec![root];
+    while let Some(node) = stack.pop() {
+        if node.children.is_empty() {
+            species.extend(node.rank.clone());
+        }
+        stack.extend(node.children.iter());
+    }
+    species.sort();
+    species.dedup();
 
+    let lineages = crate::db::query_keys(
+        conn,
+        "SELECT node, ancestor_sequence FROM genome_taxonomy WHERE node IN ({keys})",
+        &species,
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+    )?;
+    Ok(lineages.into_iter().collect())
+}
+
+/// Picks an outgroup for the given tree positions from the sister clade of their LCA.
+///
+/// The outgroup is the tip of the sister clade closest to it, so the shortest branch
+/// reaching out of the ingroup. Returns `None` when the LCA is the root of the tree.
+fn suggest_outgroup(tree: &crate::tree::GtdbTree, positions: &[usize]) -> Option<usize> {
+    let lca = tree.lca(positions)?;
+    let parent = tree.parents[lca]?;
+    let sister = *tree.children[parent].iter().find(|&&child| child != lca)?;
+
+    let mut best: Option<(f64, usize)> = None;
+    let mut distances = HashMap::from([(sister, 0.0)]);
+    for pos in tree.preorder(sister) {
+        let distance = match tree.parents[pos] {
+            Some(parent) if pos != sister => distances[&parent] + tree.nodes[pos].length,
+            _ => 0.0,
+        };
+        distances.insert(pos, distance);
+        if tree.children[pos].is_empty() && best.is_none_or(|(d, _)| distance < d) {
+            best = Some((distance, pos));
+        }
+    }
+    best.map(|(_, pos)| pos)
+}
+
+/// Roots a domain tree, see [`crate::rooting::Rooting`].
+///
+/// `outgroup` is the node id of the outgroup tip added for [`Rooting::Outgroup`].
+///
+/// [`Rooting::Outgroup`]: crate::rooting::Rooting::Outgroup
+fn root_tree(
+    conn: &Connection,
+    root: crate::tree::Node,
+    rooting: &crate::rooting::Rooting,
+    outgroup: Option<usize>,
+) -> Result<crate::tree::Node, Box<dyn std::error::Error>> {
+    use crate::rooting::{root_at, root_at_clade, root_midpoint, CladeError, Rooting};
+
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+    let rooted = match rooting {
+        Rooting::Midpoint => root_midpoint(root),
+        Rooting::Tip(name) => {
+            // Tips are also matched as written in Newick output, spaces as underscores.
+            let query = name.replace(' ', "_");
+            root_at(root, |node| {
+                node.children.is_empty()
+                    && [node.label.as_ref(), Some(&node.name), node.rank.as_ref()]
+                        .into_iter()
+                        .flatten()
+                        .any(|tip| tip.replace(' ', "_") == query)
+            })
+            .ok_or_else(|| {
+                invalid(format!(
+                    "Cannot root at {}: no such tip in the output tree",
+                    name
+                ))
+            })?
+        }
+        Rooting::Taxon(taxon) => {
+            let lineages = species_lineages(conn, &root)?;
+            root_at_clade(root, |node| {
+                node.rank.as_ref().is_some_and(|species| {
+                    species == taxon
+                        || lineages
+                            .get(species)
+                            .is_some_and(|lineage| lineage.split(';').any(|t| t.trim() == taxon))
+                })
+            })
+            .map_err(|error| {
+                invalid(match error {
+                    CladeError::NoMember => {
+                        format!(
+                            "Cannot root at {}: no tip of the output tree is in it",
+                            taxon
+                        )
+                    }
+                    CladeError::AllMembers => format!(
+                        "Cannot root at {}: every tip of the output tree is in it",
+                        taxon
+                    ),
+                    CladeError::NotMonophyletic => format!(
+                        "Cannot root at {}: it is not monophyletic in the output tree",
+                        taxon
+                    ),
+                })
+            })?
+        }
+        Rooting::Outgroup => {
+            let outgroup = outgroup
+                .ok_or_else(|| invalid("Cannot root: no outgroup was found".to_string()))?;
+            root_at(root, |node| {
+                node.children.is_empty() && node.node == outgroup
+            })
+            .ok_or_else(|| {
+                invalid(format!(
+                    "Cannot root at the outgroup, node {}: it is not in the output tree",
+                    outgroup
+                ))
+            })?
+        }
+    };
+    Ok(rooted)
+}
+
+/// Replaces representative tips by the selected genomes of their species.
+///
+/// A representative with a single selected genome is relabeled with that genome. With
+/// several, the genomes hang from it as zero-length tips, so each keeps its own label.
+fn attach_genomes(root: &mut crate::tree::Node, tips: &[Tip], domain: &str, unique_labels: bool) {
+    let mut genomes: HashMap<&str, Vec<&Tip>> = HashMap::new();
+    for tip in tips.iter().filter(|tip| tip.domain == domain) {
+        genomes.entry(tip.key.as_str()).or_default().push(tip);
+    }
+
+    let mut stack = vec![root];
+    while let Some(node) = stack.pop() {
+        if !node.children.is_empty() {
+            stack.extend(node.children.iter_mut());
+            continue;
+        }
+        let Some(selected) = genomes.get(node.name.as_str()) else {
+            continue;
+        };
+        let species = node.rank.clone().unwrap_or_default();
+        let label_of = |tip: &Tip| {
+            let accession = tip.genome.as_deref().unwrap_or(&tip.key);
+            tip.label.clone().unwrap_or_else(|| {
+                if unique_labels && !species.is_empty() {
+                    format!("{}|{}", species, accession)
+                } else {
+                    accession.to_string()
+                }
+            })
+        };
+        if let [tip] = selected.as_slice() {
+            node.label = Some(label_of(tip));
+        } else {
+            node.children = selected
+                .iter()
+                .map(|tip| crate::tree::Node {
+                    node: 0,
+                    parent: node.node,
+                    name: tip.genome.clone().unwrap_or(tip.key.clone()),
+                    length: 0.0,
+                    bootstrap: None,
+                    rank: node.rank.clone(),
+                    label: Some(label_of(tip)),
+                    children: Vec::new(),
+                })
+                .collect();
+            node.label = Some(species);
+        }
+    }
+}
+
+/// Joins per-domain trees under a common root.
+///
+/// Each domain root hangs from the new root with the placeholder `branch_length`, and is
+/// labeled with its domain when unlabeled. A single tree is returned unchanged.
+pub fn join_domain_trees(
+    mut trees: Vec<(String, crate::tree::Node)>,
+    branch_length: f64,
+) -> Option<crate::tree::Node> {
+    if trees.len() <= 1 {
+        return trees.pop().map(|(_, root)| root);
+    }
+
+    let children = trees
+        .into_iter()
+        .map(|(domain, mut root)| {
+            root.length = branch_length;
+            if root.name.is_empty() {
+                root.name = format!("d__{}", capitalize(&domain));
+            }
+            root
+        })
+        .collect();
+    Some(crate::tree::Node {
+        node: 0,
+        parent: 0,
+        name: String::new(),
+        length: 0.0,
+        bootstrap: None,
+        rank: None,
+        label: None,
+        children,
+    })
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Extracts the clade of a taxon from the GTDB tree of its domain.
+///
+/// The clade is rooted at the node decorated with the taxon, or, when the taxon is not
+/// monophyletic in the tree, at the lowest common ancestor of its genomes. The stem
+/// branch is dropped, so the returned root has no length.
+pub fn taxon_subtree(
+    db: &PathBuf,
+    taxon: &str,
+) -> Result<crate::tree::Node, Box<dyn std::error::Error>> {
+    let conn = Connection::open(db)?;
+    let domain: String = conn
+        .query_row(
+            "SELECT domain FROM genome_taxonomy WHERE node = ?1",
+            [taxon],
+            |row| row.get(0),
+        )
+        .optional()?
+        .ok_or_else(|| std::io::Error::other(format!("Taxon not found: {}", taxon)))?;
     let table_name = format!("gtdb_tree_{domain}");
-    let leaf_nodes = crate::tree::get_leaf_nodes_by_rank(&conn, &table_name, &ranks)?;
-    let newick_tree = crate::tree::build_pruned_tree(&conn, &table_name, 1, &leaf_nodes)?;
-
-    if let Some(root) = newick_tree {
-        let mut newick = String::new();
-        crate::tree::write_node_to_newick(&root, &mut newick);
-        newick.push(';');
-        Ok(newick)
+    let tree = crate::tree::GtdbTree::load(&conn, &table_name)?;
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT node FROM {}_taxa WHERE taxon = ?1",
+        table_name
+    ))?;
+    let decorated = stmt
+        .query_map([taxon], |row| row.get::<_, usize>(0))?
+        .collect::<Result<Vec<usize>>>()?;
+
+    let start = if let [node] = decorated.as_slice() {
+        tree.index.get(node).copied()
     } else {
-        Ok("".into())
+        let mut stmt = conn.prepare(&format!(
+            "SELECT node FROM {} WHERE name IN (
+                SELECT node FROM genome_taxonomy
+                WHERE rank = 'no rank' AND instr(';' || ancestor_sequence, ';' || ?1 || ';') > 0
+            )",
+            table_name
+        ))?;
+        let leaves = stmt
+            .query_map([taxon], |row| row.get::<_, usize>(0))?
+            .collect::<Result<Vec<usize>>>()?;
+        let positions: Vec<usize> = leaves
+            .iter()
+            .filter_map(|node| tree.index.get(node).copied())
+            .collect();
+        eprintln!(
+            "{} is not a decorated node of the tree, using the LCA of its {} genomes",
+            taxon,
+            positions.len()
+        );
+        tree.lca(&positions)
+    };
+    let start = start
+        .ok_or_else(|| std::io::Error::other(format!("No genome of {} in the tree", taxon)))?;
+
+    let mut root = tree.subtree(start);
+    root.length = 0.0;
+    Ok(root)
+}
+
+/// Resolves inputs to the representative genomes of a single domain tree.
+///
+/// For commands working on one tree: fails when inputs are missing, unless `skip_missing`,
+/// or when they span several domains. Returns the domain and the tip accessions.
+pub fn resolve_domain_representatives(
+    db: &PathBuf,
+    input_data: Vec<InputRecord>,
+    skip_missing: bool,
+) -> Result<(String, Vec<String>), Box<dyn std::error::Error>> {
+    let (domain, tips) = resolve_domain_tips(db, input_data, skip_missing, false)?;
+    Ok((domain, tips.into_iter().map(|tip| tip.key).collect()))
+}
+
+/// Resolves inputs to the tips of a single domain tree, see [`resolve_domain_representatives`].
+///
+/// With `genome_tips`, each input genome is kept with its representative tip as `genome`.
+pub fn resolve_domain_tips(
+    db: &PathBuf,
+    input_data: Vec<InputRecord>,
+    skip_missing: bool,
+    genome_tips: bool,
+) -> Result<(String, Vec<Tip>), Box<dyn std::error::Error>> {
+    let options = InputOptions {
+        representatives_only: !genome_tips,
+        genome_tips,
+        skip_missing,
+        ..Default::default()
+    };
+    let resolved = process_data(input_data, db, &options)?;
+    if skip_missing {
+        for r in resolved.failed() {
+            eprintln!(
+                "Skipped {}: {}",
+                r.input,
+                r.error.as_deref().unwrap_or_default()
+            );
+        }
+    } else {
+        resolved.ensure_complete()?;
+    }
+
+    let mut domains: Vec<&str> = resolved
+        .tips
+        .iter()
+        .map(|tip| tip.domain.as_str())
+        .collect();
+    domains.sort();
+    domains.dedup();
+    let domain = match domains.as_slice() {
+        [domain] => domain.to_string(),
+        [] => return Err("No genome resolved from the inputs".into()),
+        _ => return Err(format!("Genomes span several domains: {}", domains.join(", ")).into()),
+    };
+    Ok((domain, resolved.tips))
+}
+
+/// Returns the accessions of `genome_metadata` matching a filter expression.
+pub fn filter_genomes(conn: &Connection, filter: &str) -> std::io::Result<HashSet<String>> {
+    let (condition, values) = crate::filter::parse_filter(filter)?;
+    let query = format!("SELECT accession FROM genome_metadata WHERE {}", condition);
+    let mut stmt = conn.prepare(&query).map_err(std::io::Error::other)?;
+    let accessions = stmt
+        .query_map(params_from_iter(values), |row| row.get::<_, String>(0))
+        .map_err(std::io::Error::other)?
+        .collect::<Result<HashSet<String>>>()
+        .map_err(std::io::Error::other)?;
+    Ok(accessions)
+}
+
+/// A genome classified under a taxon input.
+#[derive(Debug, Clone)]
+pub struct TaxonGenome {
+    pub accession: String,
+    pub species: String,
+    pub domain: String,
+    pub representative: bool,
+}
+
+/// Returns the genomes classified under each of the given taxa.
+///
+/// The taxonomy is walked down one rank at a time from all taxa at once, through the
+/// indexed `parent` column, so the cost is that of the genomes found. Genomes are sorted
+/// by accession.
+pub fn taxa_genomes(
+    conn: &Connection,
+    taxa: &[String],
+) -> std::io::Result<HashMap<String, Vec<TaxonGenome>>> {
+    let mut genomes: HashMap<String, Vec<TaxonGenome>> = HashMap::new();
+    // Node -> the input taxa it is classified under.
+    let mut frontier: HashMap<String, Vec<String>> = HashMap::new();
+    for taxon in taxa {
+        frontier
+            .entry(taxon.clone())
+            .or_default()
+            .push(taxon.clone());
+    }
+
+    while !frontier.is_empty() {
+        let keys: Vec<String> = frontier.keys().cloned().collect();
+        let rows = crate::db::query_keys(
+            conn,
+            "SELECT t.parent, t.node, t.rank, t.domain, COALESCE(m.gtdb_representative, 0)
+            FROM genome_taxonomy t LEFT JOIN genome_metadata m ON m.accession = t.node
+            WHERE t.parent IN ({keys})",
+            &keys,
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, bool>(4)?,
+                ))
+            },
+        )
+        .map_err(std::io::Error::other)?;
+
+        let mut next: HashMap<String, Vec<String>> = HashMap::new();
+        for (parent, node, rank, domain, representative) in rows {
+            let inputs = &frontier[&parent];
+            if rank == "no rank" {
+                for input in inputs {
+                    genomes.entry(input.clone()).or_default().push(TaxonGenome {
+                        accession: node.clone(),
+                        species: parent.clone(),
+                        domain: domain.clone(),
+                        representative,
+                    });
+                }
+            } else {
+                next.entry(node).or_default().extend(inputs.iter().cloned());
+            }
+        }
+        frontier = next;
+    }
+
+    for list in genomes.values_mut() {
+        list.sort_by(|a, b| a.accession.cmp(&b.accession));
+    }
+    Ok(genomes)
+}
+
+/// How an input item was interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputKind {
+    /// A GTDB taxon name such as `s__Escherichia coli` or `g__Escherichia`.
+    Taxon,
+    /// An NCBI taxid.
+    Taxid,
+    /// A genome accession such as `GCF_000005845.2`.
+    Accession,
+    Unrecognized,
+}
+
+impl std::fmt::Display for InputKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            InputKind::Taxon => "taxon",
+            InputKind::Taxid => "taxid",
+            InputKind::Accession => "accession",
+            InputKind::Unrecognized => "unrecognized",
+        };
+        write!(f, "{}", kind)
     }
 }
 
-pub fn process_data(data: Vec<String>, db: &PathBuf) -> std::io::Result<Vec<String>> {
+/// The outcome of resolving one input item.
+#[derive(Debug, Clone)]
+pub struct Resolution {
+    pub input: String,
+    pub kind: InputKind,
+    /// Keys of the tips the input resolved to.
+    pub resolved: Vec<String>,
+    /// Why the input did not resolve, if it did not.
+    pub error: Option<String>,
+}
+
+/// Resolved tips, and how each input was resolved.
+#[derive(Debug, Clone, Default)]
+pub struct ResolvedInput {
+    pub tips: Vec<Tip>,
+    pub report: Vec<Resolution>,
+}
+
+impl ResolvedInput {
+    pub fn failed(&self) -> impl Iterator<Item = &Resolution> {
+        self.report.iter().filter(|r| r.error.is_some())
+    }
+
+    /// Fails with the list of unresolved inputs, if any.
+    pub fn ensure_complete(&self) -> std::io::Result<()> {
+        let failed: Vec<&str> = self.failed().map(|r| r.input.as_str()).collect();
+        if failed.is_empty() {
+            return Ok(());
+        }
+        Err(std::io::Error::other(format!(
+            "Not found in the database. Missing: {}",
+            failed.join(", ")
+        )))
+    }
+
+    /// Renders the report as TSV: input, interpretation, resolved tips, failure reason.
+    pub fn report_tsv(&self) -> String {
+        let mut tsv = String::from("input\tinterpreted_as\tresolved_to\terror\n");
+        for r in &self.report {
+            tsv.push_str(&format!(
+                "{}\t{}\t{}\t{}\n",
+                r.input,
+                r.kind,
+                r.resolved.join(","),
+                r.error.as_deref().unwrap_or("")
+            ));
+        }
+        tsv
+    }
+}
+
+/// Resolves the inputs to tree tips.
+///
+/// By default the keys are species names, matched against the `rank` column of the tree.
+/// With `representatives_only` or `genome_tips` they are the accessions of the species
+/// representatives, matched against the tip names; with `genome_tips` each tip also keeps
+/// the genome it was selected for. Tips are deduplicated. Inputs that do not resolve are
+/// recorded in the report rather than failing, see [`ResolvedInput::ensure_complete`].
+pub fn process_data(
+    data: Vec<InputRecord>,
+    db: &PathBuf,
+    options: &InputOptions,
+) -> std::io::Result<ResolvedInput> {
     let mut species = Vec::new();
     let mut ncbi_taxids = Vec::new();
     let mut ncbi_ids = Vec::new();
+    // (input record, kind, classified key)
+    let mut classified = Vec::new();
 
-    let conn = Connection::open(db).expect("Failed to open database");
+    let conn = Connection::open(db).map_err(std::io::Error::other)?;
     let valid_prefixes = ["c__", "d__", "f__", "g__", "o__", "p__", "s__"];
+    let accession_re = regex::Regex::new(r"(?:[A-Za-z]{2}_)?[A-Za-z]{3}_(\d+\.\d+)").unwrap();
 
     // Classify the input data
-    for item in data {
-        if valid_prefixes
+    for record in data {
+        let item = &record.item;
+        let (kind, key) = if valid_prefixes
             .iter()
             .any(|&prefix| item.starts_with(prefix))
         {
             species.push(item.to_string());
+            (InputKind::Taxon, item.to_string())
         } else if item.chars().all(char::is_numeric) {
             ncbi_taxids.push(item.to_string());
-        } else if let Some(captures) = regex::Regex::new(r"(?:[A-Za-z]{2}_)?[A-Za-z]{3}_(\d+\.\d+)")
-            .unwrap()
-            .captures(&item)
-        {
+            (InputKind::Taxid, item.to_string())
+        } else if let Some(captures) = accession_re.captures(item) {
             let ncbi_id = captures.get(1).unwrap().as_str();
             ncbi_ids.push(ncbi_id.to_string());
+            (InputKind::Accession, ncbi_id.to_string())
         } else {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to process item: {}", item),
-            ));
-        }
+            (InputKind::Unrecognized, String::new())
+        };
+        classified.push((record, kind, key));
     }
 
-    let mut not_found = Vec::new();
-    let mut results = Vec::new();
+    // Genomes are only kept if they pass the filter, taxon inputs keep their passing genomes
+    let passing = match &options.filter {
+        Some(filter) => Some(filter_genomes(&conn, filter)?),
+        None => None,
+    };
 
-    if !species.is_empty() {
-        let placeholders: Vec<String> = species.iter().map(|_| "?".to_string()).collect();
-        let query = format!(
-            "SELECT node FROM genome_taxonomy WHERE node IN ({})",
-            placeholders.join(", ")
-        );
-        let mut stmt = conn.prepare(&query).expect("Failed to prepare query");
-        let rows: Vec<String> = stmt
-            .query_map(
-                params_from_iter(species.iter().map(|s| s.as_str())),
-                |row| row.get::<_, String>(0),
-            )
-            .expect("Failed to query")
-            .filter_map(Result::ok)
-            .collect(); // Collect rows into a vector
-
-        // Check if all species exist in the database
-        if rows.len() != species.len() {
-            // Calculate the difference between expected and found species
-            let missing_species: Vec<&String> =
-                species.iter().filter(|s| !rows.contains(s)).collect();
-
-            not_found.extend(missing_species);
-        } else {
-            results.extend(species);
-        }
+    let taxa: HashMap<String, String> = crate::db::query_keys(
+        &conn,
+        "SELECT node, domain FROM genome_taxonomy WHERE node IN ({keys})",
+        &species,
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+    )
+    .map_err(std::io::Error::other)?
+    .into_iter()
+    .collect();
+
+    let mut genomes: HashMap<(InputKind, String), Vec<GenomeRow>> = HashMap::new();
+    let taxid_rows = crate::db::query_keys(
+        &conn,
+        "SELECT t.ncbi_taxid, t.parent, t.node, m.gtdb_genome_representative, t.domain
+        FROM genome_taxonomy t LEFT JOIN genome_metadata m ON m.accession = t.node
+        WHERE t.ncbi_taxid IN ({keys})",
+        &ncbi_taxids,
+        |row| {
+            Ok(GenomeRow {
+                input: row.get::<_, i64>(0)?.to_string(),
+                species: row.get(1)?,
+                accession: row.get(2)?,
+                representative: row.get(3)?,
+                domain: row.get(4)?,
+            })
+        },
+    )
+    .map_err(std::io::Error::other)?;
+    for row in taxid_rows {
+        genomes
+            .entry((InputKind::Taxid, row.input.clone()))
+            .or_default()
+            .push(row);
+    }
+    let accession_rows = crate::db::query_keys(
+        &conn,
+        "SELECT t.ncbi_id, t.parent, t.node, m.gtdb_genome_representative, t.domain
+        FROM genome_taxonomy t LEFT JOIN genome_metadata m ON m.accession = t.node
+        WHERE t.ncbi_id IN ({keys})",
+        &ncbi_ids,
+        |row| {
+            Ok(GenomeRow {
+                input: row.get(0)?,
+                species: row.get(1)?,
+                accession: row.get(2)?,
+                representative: row.get(3)?,
+                domain: row.get(4)?,
+            })
+        },
+    )
+    .map_err(std::io::Error::other)?;
+    for row in accession_rows {
+        genomes
+            .entry((InputKind::Accession, row.input.clone()))
+            .or_default()
+            .push(row);
     }
 
-    // Batch query for ncbi_taxids
-    if !ncbi_taxids.is_empty() {
-        let placeholders: Vec<String> = ncbi_taxids.iter().map(|_| "?".to_string()).collect();
-        let query = format!(
-            "SELECT ncbi_taxid, parent FROM genome_taxonomy WHERE ncbi_taxid IN ({})",
-            placeholders.join(", ")
-        );
-        let mut stmt = conn.prepare(&query).expect("Failed to prepare query");
-        let rows: Vec<(String, String)> = stmt
-            .query_map(
-                params_from_iter(ncbi_taxids.iter().map(|s| s.as_str())),
-                |row| {
-                    let ncbi_taxid: String = row.get(0)?;
-                    let parent: String = row.get(1)?;
-                    Ok((ncbi_taxid, parent)) // Wrap the tuple in Ok
-                },
-            )
-            .expect("Failed to query")
-            .filter_map(Result::ok)
-            .collect();
+    // Genomes of all taxon inputs, resolved at once, to pick representatives or filter.
+    let taxon_genomes = if options.by_representative() || passing.is_some() {
+        taxa_genomes(&conn, &species)?
+    } else {
+        HashMap::new()
+    };
 
-        if rows.len() != ncbi_taxids.len() {
-            let selected: Vec<_> = rows.iter().map(|r| r.0.clone()).collect();
-            let missing_species: Vec<&String> =
-                ncbi_ids.iter().filter(|s| !selected.contains(s)).collect();
-            not_found.extend(missing_species);
-        } else {
-            for row in rows {
-                results.push(row.1);
-            }
+    let passes = |accession: &str| passing.as_ref().is_none_or(|p| p.contains(accession));
+
+    let mut resolved = ResolvedInput::default();
+    for (record, kind, key) in classified {
+        let mut tips = Vec::new();
+        let error = match kind {
+            InputKind::Unrecognized => Some("unrecognized input format".to_string()),
+            InputKind::Taxon => match taxa.get(&key) {
+                None => Some("taxon not found in the database".to_string()),
+                Some(_) if options.by_representative() => {
+                    tips = taxon_genomes
+                        .get(&key)
+                        .into_iter()
+                        .flatten()
+                        .filter(|genome| genome.representative)
+                        .filter(|genome| passes(&genome.accession))
+                        .map(|genome| Tip {
+                            key: genome.accession.clone(),
+                            domain: genome.domain.clone(),
+                            genome: options.genome_tips.then(|| genome.accession.clone()),
+                            label: None,
+                        })
+                        .collect();
+                    if !tips.is_empty() {
+                        None
+                    } else if passing.is_some() {
+                        Some("filtered out by the genome filter".to_string())
+                    } else {
+                        Some("no species representative under this taxon".to_string())
+                    }
+                }
+                // Filtered taxa are narrowed to the species of their passing genomes
+                Some(_) if passing.is_some() => {
+                    let mut species: Vec<&TaxonGenome> = taxon_genomes
+                        .get(&key)
+                        .into_iter()
+                        .flatten()
+                        .filter(|genome| passes(&genome.accession))
+                        .collect();
+                    species.sort_by(|a, b| a.species.cmp(&b.species));
+                    species.dedup_by(|a, b| a.species == b.species);
+                    tips = species
+                        .into_iter()
+                        .map(|genome| Tip {
+                            key: genome.species.clone(),
+                            domain: genome.domain.clone(),
+                            genome: None,
+                            label: None,
+                        })
+                        .collect();
+                    if tips.is_empty() {
+                        Some("filtered out by the genome filter".to_string())
+                    } else {
+                        None
+                    }
+                }
+                Some(domain) => {
+                    tips.push(Tip {
+                        key: key.clone(),
+                        domain: domain.clone(),
+                        genome: None,
+                        label: None,
+                    });
+                    None
+                }
+            },
+            InputKind::Taxid | InputKind::Accession => match genomes.get(&(kind, key)) {
+                None => Some(format!("{} not found in the database", kind)),
+                Some(rows) => {
+                    tips = rows
+                        .iter()
+                        .filter(|row| passes(&row.accession))
+                        .map(|row| genome_tip(row, options))
+                        .collect();
+                    if tips.is_empty() {
+                        Some("filtered out by the genome filter".to_string())
+                    } else {
+                        None
+                    }
+                }
+            },
+        };
+
+        for tip in tips.iter_mut() {
+            tip.label = record.label.clone();
         }
+        let mut seen_keys = HashSet::new();
+        let keys: Vec<String> = tips
+            .iter()
+            .map(|tip| tip.genome.clone().unwrap_or(tip.key.clone()))
+            .filter(|key| seen_keys.insert(key.clone()))
+            .collect();
+        resolved.report.push(Resolution {
+            input: record.item,
+            kind,
+            resolved: keys,
+            error,
+        });
+        resolved.tips.extend(tips);
+    }
+
+    let mut seen = HashSet::new();
+    resolved
+        .tips
+        .retain(|tip| seen.insert((tip.key.clone(), tip.domain.clone(), tip.genome.clone())));
+    Ok(resolved)
+}
+
+/// A genome matched by a taxid or accession input.
+struct GenomeRow {
+    input: String,
+    species: String,
+    accession: String,
+    representative: Option<String>,
+    domain: String,
+}
+
+fn genome_tip(row: &GenomeRow, options: &InputOptions) -> Tip {
+    let key = if options.by_representative() {
+        row.representative.clone().unwrap_or(row.accession.clone())
+    } else {
+        row.species.clone()
+    };
+    Tip {
+        key,
+        domain: row.domain.clone(),
+        genome: options.genome_tips.then(|| row.accession.clone()),
+        label: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gtdb::tests::release_db;
+    use crate::tree::to_newick;
+
+    /// The bacterial tree of the test release, tips named by species.
+    fn species_tree(conn: &Connection) -> crate::tree::Node {
+        crate::tree::GtdbTree::load(conn, "gtdb_tree_bacteria")
+            .unwrap()
+            .subtree(0)
     }
 
-    // Batch query for ncbi_ids
-    if !ncbi_ids.is_empty() {
-        let placeholders: Vec<String> = ncbi_ids.iter().map(|_| "?".to_string()).collect();
-        let query = format!(
-            "SELECT ncbi_id, parent FROM genome_taxonomy WHERE ncbi_id IN ({})",
-            placeholders.join(", ")
+    #[test]
+    fn collapse_to_rank_groups_tips_by_lineage() {
+        let db = release_db("collapse-to-rank");
+        let conn = Connection::open(&db).unwrap();
+
+        let mut root = species_tree(&conn);
+        collapse_to_rank(&conn, &mut root, "genus").unwrap();
+        assert_eq!(
+            crate::tree::to_newick(&root),
+            "((g__Esch|2:0.050000[100.00],g__Salm|1:0.300000)f__Ent:0.020000[95.50],\
+            g__Baci|2:0.010000[80.00])d__Bacteria;"
         );
-        let mut stmt = conn.prepare(&query).expect("Failed to prepare query");
-        let rows: Vec<(String, String)> = stmt
-            .query_map(
-                params_from_iter(ncbi_ids.iter().map(|s| s.as_str())),
-                |row| {
-                    let ncbi_id: String = row.get(0)?;
-                    let parent: String = row.get(1)?;
-                    Ok((ncbi_id, parent)) // Wrap the tuple in Ok
-                },
-            )
-            .expect("Failed to query")
-            .filter_map(Result::ok)
-            .collect();
 
-        if rows.len() != ncbi_ids.len() {
-            let selected: Vec<_> = rows.iter().map(|r| r.0.clone()).collect();
-            let missing_species: Vec<&String> =
-                ncbi_ids.iter().filter(|s| !selected.contains(s)).collect();
+        let mut root = species_tree(&conn);
+        collapse_to_rank(&conn, &mut root, "f__").unwrap();
+        assert_eq!(
+            crate::tree::to_newick(&root),
+            "(f__Ent|3:0.020000[95.50],f__Bacf|2:0.010000[80.00])d__Bacteria;"
+        );
 
-            not_found.extend(missing_species);
-        } else {
-            for row in rows {
-                results.push(row.1);
-            }
-        }
+        let mut root = species_tree(&conn);
+        assert!(collapse_to_rank(&conn, &mut root, "tribe").is_err());
+        std::fs::remove_dir_all(db.parent().unwrap()).unwrap();
     }
 
-    if !not_found.is_empty() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!(
-                "Not found in the database. Missing: {}",
-                not_found
-                    .iter()
-                    .map(|s| s.as_str())
-                    .collect::<Vec<&str>>()
-                    .join(", ")
-            ),
-        ));
+    #[test]
+    fn subtree_of_a_decorated_taxon() {
+        let db = release_db("subtree-decorated");
+        let root = taxon_subtree(&db, "g__Esch").unwrap();
+        assert_eq!(
+            to_newick(&root),
+            "(s__Esch_coli:0.100000,s__Esch_alb:0.200000)g__Esch[100.00];"
+        );
+        std::fs::remove_dir_all(db.parent().unwrap()).unwrap();
     }
 
-    Ok(results)
+    #[test]
+    fn subtree_of_an_undecorated_taxon_is_the_clade_of_its_genomes() {
+        let db = release_db("subtree-undecorated");
+        let root = taxon_subtree(&db, "g__Baci").unwrap();
+        assert_eq!(
+            to_newick(&root),
+            "(s__Baci_sub:0.400000,s__Baci_cer:0.150000)[80.00];"
+        );
+        assert!(taxon_subtree(&db, "g__Unknown").is_err());
+        std::fs::remove_dir_all(db.parent().unwrap()).unwrap();
+    }
 }
@@ -1,35 +1,172 @@
 use crate::utils::download_file;
-use chrono::{Datelike, NaiveDate};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
 use flate2::read::GzDecoder;
 use gtdb_tree;
 use regex::Regex;
 use reqwest::blocking::Client;
 use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::OpenOptions;
 use std::fs::{self, File};
 use std::io::{self, BufRead, Read};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReleaseInfo {
     pub version: String,
     pub date: NaiveDate,
     pub sub_versions: Vec<SubVersionInfo>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SubVersionInfo {
     pub version: String,
     pub date: NaiveDate,
     pub url: String,
 }
 
-/// List all GTDB release versions and their sub-versions, and save to file if not exists.
+/// GTDB releases and their sub-versions, newest first, as cached under `taxo_path`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReleaseIndex {
+    pub fetched_at: DateTime<Utc>,
+    pub releases: Vec<ReleaseInfo>,
+}
+
+/// File name of the cached [`ReleaseIndex`] in `taxo_path`.
+const RELEASE_INDEX_FILE: &str = "gtdb_releases.json";
+
+/// How the cached release index is used.
+#[derive(Debug, Clone, Copy)]
+pub struct ReleaseCache {
+    /// Fetch the index again even if the cache is fresh.
+    pub refresh: bool,
+    /// Age after which the cache is fetched again.
+    pub ttl: Duration,
+}
+
+impl ReleaseIndex {
+    /// Reads the cached index, `None` if there is none or it cannot be read, so that a
+    /// corrupt cache is fetched again.
+    pub fn read_cache(taxo_path: &Path) -> Option<Self> {
+        let path = taxo_path.join(RELEASE_INDEX_FILE);
+        if !path.exists() {
+            return None;
+        }
+        let index = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()));
+        match index {
+            Ok(index) => Some(index),
+            Err(e) => {
+                eprintln!("Ignoring invalid release cache {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn write_cache(&self, taxo_path: &Path) -> io::Result<()> {
+        fs::create_dir_all(taxo_path)?;
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        fs::write(taxo_path.join(RELEASE_INDEX_FILE), json)
+    }
+
+    /// Loads the index from the cache, fetching it from the GTDB website when the cache is
+    /// missing, older than the TTL or a refresh is requested.
+    ///
+    /// A stale cache is still used, with a warning, when the website cannot be reached.
+    pub fn load(taxo_path: &Path, cache: ReleaseCache) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::load_with(taxo_path, cache, fetch_releases)
+    }
+
+    /// [`ReleaseIndex::load`] with the releases fetched by `fetch`.
+    fn load_with<F>(
+        taxo_path: &Path,
+        cache: ReleaseCache,
+        fetch: F,
+    ) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        F: FnOnce() -> Result<Vec<ReleaseInfo>, Box<dyn std::error::Error>>,
+    {
+        let cached = ReleaseIndex::read_cache(taxo_path);
+        if let Some(index) = &cached {
+            if !cache.refresh && Utc::now() - index.fetched_at < cache.ttl {
+                return Ok(index.clone());
+            }
+        }
+        match fetch() {
+            Ok(releases) => {
+                let index = ReleaseIndex {
+                    fetched_at: Utc::now(),
+                    releases,
+                };
+                if let Err(e) = index.write_cache(taxo_path) {
+                    eprintln!("Could not cache the GTDB releases: {}", e);
+                }
+                Ok(index)
+            }
+            Err(e) => match cached {
+                Some(index) => {
+                    eprintln!(
+                        "Could not fetch GTDB releases ({}), using the list cached on {}",
+                        e,
+                        index.fetched_at.format("%Y-%m-%d %H:%M")
+                    );
+                    Ok(index)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Finds a sub-version, the latest one by default.
+    pub fn sub_version(
+        &self,
+        sub_version: Option<&str>,
+    ) -> Result<SubVersionInfo, Box<dyn std::error::Error>> {
+        let mut sub_versions = self.releases.iter().flat_map(|r| r.sub_versions.iter());
+        let found = match sub_version {
+            Some(sub_version) => sub_versions.find(|v| v.version == sub_version),
+            None => sub_versions.max_by_key(|v| v.date),
+        };
+        match (found, sub_version) {
+            (Some(info), _) => Ok(info.clone()),
+            (None, Some(sub_version)) => Err(format!(
+                "Sub-version {} not found, known sub-versions: {}",
+                sub_version,
+                self.sub_version_names().join(", ")
+            )
+            .into()),
+            (None, None) => Err("No sub-versions available".into()),
+        }
+    }
+
+    /// Names of all sub-versions, newest release first.
+    pub fn sub_version_names(&self) -> Vec<String> {
+        self.releases
+            .iter()
+            .flat_map(|r| r.sub_versions.iter().map(|v| v.version.clone()))
+            .collect()
+    }
+}
+
+/// List all GTDB release versions and their sub-versions, from the cache in `taxo_path`
+/// when fresh, and return the requested sub-version, the latest by default.
 pub fn list_releases(
+    taxo_path: &Path,
+    cache: ReleaseCache,
     should_print: bool,
     target_sub_version: Option<String>,
 ) -> Result<SubVersionInfo, Box<dyn std::error::Error>> {
+    let index = ReleaseIndex::load(taxo_path, cache)?;
+    if should_print {
+        print_releases(&index.releases);
+    }
+    index.sub_version(target_sub_version.as_deref())
+}
+
+/// Scrapes the GTDB releases and their sub-versions from the GTDB website, newest first.
+fn fetch_releases() -> Result<Vec<ReleaseInfo>, Box<dyn std::error::Error>> {
     let client = Client::new();
     let base_url = "https://data.gtdb.ecogenomic.org/releases/";
     let response = client.get(base_url).send()?.text()?;
@@ -38,8 +175,6 @@ pub fn list_releases(
         r#"<tr>\s*<td><img[^>]*></td>\s*<td class="n">\s*<a href="([^"]+)/">[^<]*</a>\s*/\s*</td>\s*<td class="m">([^<]+)</td>"#,
     )?;
 
-    let mut sub_versions = Vec::new();
-
     let mut releases: HashMap<String, ReleaseInfo> = re
         .captures_iter(&response)
         .filter_map(|cap| {
@@ -63,32 +198,11 @@ pub fn list_releases(
 
     for (version, info) in releases.iter_mut() {
         let release_url = format!("{}{}/", base_url, version);
-        let sub = get_sub_versions(&client, &release_url)?;
-        sub_versions.extend(sub.clone());
-        info.sub_versions = sub.clone();
-    }
-    let mut sorted_releases: Vec<_> = releases.into_iter().collect();
-    sorted_releases.sort_by(|a, b| b.1.date.cmp(&a.1.date));
-
-    if should_print {
-        print_releases(&sorted_releases);
+        info.sub_versions = get_sub_versions(&client, &release_url)?;
     }
-
-    let sub_version_info = if let Some(sub_version) = target_sub_version {
-        sub_versions
-            .iter()
-            .find(|v| v.version == sub_version)
-            .ok_or(format!("Sub-version {} not found", sub_version))?
-            .clone() // Clone the URL to avoid moving out of the reference
-    } else {
-        sub_versions
-            .iter()
-            .max_by_key(|v| v.date)
-            .ok_or("No sub-versions available")?
-            .clone()
-    };
-
-    Ok(sub_version_info)
+    let mut sorted_releases: Vec<_> = releases.into_values().collect();
+    sorted_releases.sort_by_key(|r| std::cmp::Reverse(r.date));
+    Ok(sorted_releases)
 }
 
 /// Get sub-versions for a specific release.
@@ -214,9 +328,9 @@ pub fn parse_domain_files(dir_path: &PathBuf) -> Result<Vec<DomainFile>, std::io
 }
 
 /// Print releases to console.
-fn print_releases(releases: &Vec<(String, ReleaseInfo)>) {
+fn print_releases(releases: &[ReleaseInfo]) {
     println!("GTDB Release versions and sub-versions:");
-    for (_, info) in releases {
+    for info in releases {
         println!("{} ({})", info.version, info.date.format("%Y-%m-%d"));
         for sub_version in &info.sub_versions {
             println!(
@@ -278,6 +392,8 @@ pub fn parse_metadata(db: &PathBuf, domain_files: &Vec<DomainFile>) -> io::Resul
     let mut conn = Connection::open(db).expect("failed to open database");
     // Create tables if they don't exist
     crate::db::create_genome_taxonomy_table(&mut conn).expect("failed to create tables");
+    crate::db::create_genome_metadata_table(&conn).expect("failed to create tables");
+    let mut translation_counts = crate::translate::TranslationCounts::default();
 
     for domain_file in domain_files.iter() {
         let (path, domain) = match domain_file {
@@ -313,8 +429,19 @@ pub fn parse_metadata(db: &PathBuf, domain_files: &Vec<DomainFile>) -> io::Resul
             .iter()
             .position(|&r| r == "ncbi_taxid")
             .expect("NCBI taxid column not found");
+        let ncbi_taxonomy_index = header_fields.iter().position(|&r| r == "ncbi_taxonomy");
+        let column = |name: &str| header_fields.iter().position(|&r| r == name);
+        let completeness_index = column("checkm_completeness");
+        let contamination_index = column("checkm_contamination");
+        let genome_size_index = column("genome_size");
+        let gc_index = column("gc_percentage");
+        let n50_index = column("n50_contigs");
+        let representative_index = column("gtdb_representative");
+        let genome_representative_index = column("gtdb_genome_representative");
+        let species_taxid_index = column("ncbi_species_taxid");
 
         let mut taxonomies = Vec::new();
+        let mut genomes = Vec::new();
         for line in lines {
             let line = line?;
             let fields: Vec<&str> = line.split('\t').collect();
@@ -322,6 +449,24 @@ pub fn parse_metadata(db: &PathBuf, domain_files: &Vec<DomainFile>) -> io::Resul
             let accession = fields[accession_index];
             let gtdb_taxonomy = fields[taxonomy_index]; // gtdb_taxonomy is at index 19
             let ncbi_taxid: Option<i64> = fields[taxid_index].parse().ok(); // Assuming ncbi_taxid is at index 84
+            if let Some(index) = ncbi_taxonomy_index {
+                translation_counts.add_genome(gtdb_taxonomy, fields[index]);
+            }
+            let field = |index: Option<usize>| index.and_then(|i| fields.get(i)).copied();
+            genomes.push(crate::db::GenomeMetadata {
+                accession: accession.to_string(),
+                checkm_completeness: field(completeness_index).and_then(|v| v.parse().ok()),
+                checkm_contamination: field(contamination_index).and_then(|v| v.parse().ok()),
+                genome_size: field(genome_size_index).and_then(|v| v.parse().ok()),
+                gc_percentage: field(gc_index).and_then(|v| v.parse().ok()),
+                n50_contigs: field(n50_index).and_then(|v| v.parse().ok()),
+                gtdb_representative: field(representative_index).map(|v| v == "t"),
+                gtdb_genome_representative: field(genome_representative_index)
+                    .filter(|v| !v.is_empty() && *v != "none")
+                    .map(str::to_string),
+                ncbi_species_taxid: field(species_taxid_index).and_then(|v| v.parse().ok()),
+                domain: domain.to_string(),
+            });
 
             // Parse gtdb_taxonomy
             let taxonomy_parts: Vec<&str> = gtdb_taxonomy.split(';').collect();
@@ -366,17 +511,67 @@ pub fn parse_metadata(db: &PathBuf, domain_files: &Vec<DomainFile>) -> io::Resul
                     .expect("batch insert taxonomy failed");
                 taxonomies.clear(); // Clear the vector after batch insert
             }
+            if genomes.len() >= 1000 {
+                crate::db::batch_insert_genome_metadata(&mut conn, &genomes)
+                    .expect("batch insert genome metadata failed");
+                genomes.clear();
+            }
         }
         // Insert any remaining records
         if !taxonomies.is_empty() {
             crate::db::batch_insert_taxonomy(&mut conn, &taxonomies)
                 .expect("batch insert taxonomy failed");
         }
+        if !genomes.is_empty() {
+            crate::db::batch_insert_genome_metadata(&mut conn, &genomes)
+                .expect("batch insert genome metadata failed");
+        }
     }
 
+    translation_counts
+        .write(&mut conn)
+        .expect("Failed to write translation tables");
+
     Ok(())
 }
 
+/// Parses a Newick node like the default parser, with a NaN support when the node has none.
+///
+/// The default parser reports an absent support as 0, which a support of 0 cannot be told
+/// apart from.
+fn parse_tree_node(node_str: &str) -> Result<(String, f64, f64), gtdb_tree::node::ParseError> {
+    let (name, bootstrap, length) = gtdb_tree::tree::parse_node_default(node_str)?;
+    Ok((
+        name,
+        if has_support(node_str) {
+            bootstrap
+        } else {
+            f64::NAN
+        },
+        length,
+    ))
+}
+
+/// Whether `parse_node_default` reads a support value from a node: either `name:length[support]`
+/// or a label whose first `:`-separated part is a number up to 100, e.g. `'100.0:p__Firmicutes'`.
+fn has_support(node_str: &str) -> bool {
+    if node_str
+        .rsplit_once('[')
+        .is_some_and(|(name_length, _)| name_length.contains(':'))
+    {
+        return true;
+    }
+    let Some((label, _length)) = node_str.rsplit_once(':') else {
+        return false;
+    };
+    let label = label.trim_end_matches(';').trim_matches('\'');
+    label
+        .split(':')
+        .next()
+        .and_then(|support| support.parse::<f64>().ok())
+        .is_some_and(|support| support <= 100.1)
+}
+
 /// Parses the tree files and inserts data into the SQLite database.
 pub fn parse_tree(db: &PathBuf, domain_files: &Vec<DomainFile>) -> io::Result<()> {
     println!("Parsing tree");
@@ -397,14 +592,27 @@ pub fn parse_tree(db: &PathBuf, domain_files: &Vec<DomainFile>) -> io::Result<()
         reader.read_to_string(&mut buffer)?;
 
         // Process each line in the tree file
-        let nodes = gtdb_tree::tree::parse_tree(&buffer, gtdb_tree::tree::NodeParser::default())
-            .expect("Failed to parse tree");
+        let parser = gtdb_tree::tree::NodeParser::Custom(Box::new(parse_tree_node));
+        let nodes = gtdb_tree::tree::parse_tree(&buffer, parser).expect("Failed to parse tree");
         conn.execute(&format!("DELETE FROM {}", table_name), [])
             .expect(&format!("Failed to truncate table {}", table_name));
 
         let mut batch = Vec::new();
+        let mut taxa_batch = Vec::new();
         for node in nodes {
-            batch.push((node.id, node.parent, node.name, node.length, node.bootstrap));
+            // Names are read without support, e.g. `p__Firmicutes; c__Bacilli`, but the root
+            // name keeps its quotes.
+            let name = node.name.trim_matches('\'');
+            for (rank, taxon) in crate::translate::taxonomy_ranks(name) {
+                taxa_batch.push((node.id, rank.to_string(), taxon.to_string()));
+            }
+            batch.push((
+                node.id,
+                node.parent,
+                name.to_string(),
+                node.length,
+                (!node.bootstrap.is_nan()).then_some(node.bootstrap),
+            ));
             // Batch insert every 1000 records
             if batch.len() >= 1000 {
                 crate::db::batch_insert_gtdb_tree(&mut conn, table_name, &batch)
@@ -417,9 +625,212 @@ pub fn parse_tree(db: &PathBuf, domain_files: &Vec<DomainFile>) -> io::Result<()
             crate::db::batch_insert_gtdb_tree(&mut conn, table_name, &batch)
                 .expect("Failed to batch insert gtdb_tree nodes");
         }
+        crate::db::batch_insert_gtdb_tree_taxa(&mut conn, table_name, &taxa_batch)
+            .expect("Failed to insert gtdb_tree taxa");
         crate::db::update_gtdb_tree_species(&mut conn, table_name)
             .expect("Failed to update gtdb_tree species");
+        crate::red::update_red(&mut conn, table_name).expect("Failed to compute gtdb_tree RED");
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// An empty directory for one test, under the system temporary directory.
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("clade-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// GTDB bacterial tree of five species representatives, with its decorations.
+    const BACTERIA_TREE: &str =
+        "((('RS_GCF_000001.1':0.1,'GB_GCA_000002.1':0.2)'100.0:g__Esch':0.05,\
+        'RS_GCF_000003.1':0.3)'95.5:o__Ento; f__Ent':0.02,\
+        (RS_GCF_000004.1:0.4,RS_GCF_000005.1:0.15)80.0:0.01)'d__Bacteria';";
+
+    /// Genomes of the tree, with two more of `s__Esch coli` and one of `s__Esch alb`:
+    /// accession, representative, species representative, completeness, contamination.
+    const BACTERIA_GENOMES: [(&str, &str, &str, f64, f64); 8] = [
+        ("RS_GCF_000001.1", "t", "RS_GCF_000001.1", 99.1, 0.5),
+        ("GB_GCA_000002.1", "t", "GB_GCA_000002.1", 92.0, 3.0),
+        ("RS_GCF_000003.1", "t", "RS_GCF_000003.1", 98.0, 1.0),
+        ("RS_GCF_000004.1", "t", "RS_GCF_000004.1", 97.0, 0.2),
+        ("RS_GCF_000005.1", "t", "RS_GCF_000005.1", 96.0, 6.0),
+        ("RS_GCF_000011.1", "f", "RS_GCF_000001.1", 99.5, 0.1),
+        ("RS_GCF_000012.1", "f", "RS_GCF_000001.1", 90.0, 8.0),
+        ("RS_GCF_000013.1", "f", "GB_GCA_000002.1", 95.0, 1.0),
+    ];
+
+    /// Parses a small bacterial release into `{name}/release.db` under the temporary
+    /// directory, the species being `s__Esch coli`, `s__Esch alb` (genus `g__Esch`),
+    /// `s__Salm ent` and `s__Baci sub`, `s__Baci cer` (genus `g__Baci`).
+    pub(crate) fn release_db(name: &str) -> PathBuf {
+        let dir = temp_dir(name);
+        let lineage = |representative: &str| {
+            let (genus, species) = match representative {
+                "RS_GCF_000001.1" => ("g__Esch", "s__Esch coli"),
+                "GB_GCA_000002.1" => ("g__Esch", "s__Esch alb"),
+                "RS_GCF_000003.1" => ("g__Salm", "s__Salm ent"),
+                "RS_GCF_000004.1" => ("g__Baci", "s__Baci sub"),
+                _ => ("g__Baci", "s__Baci cer"),
+            };
+            let family = if genus == "g__Baci" {
+                "p__Firm;c__Bac;o__Bacl;f__Bacf"
+            } else {
+                "p__Pro;c__Gam;o__Ento;f__Ent"
+            };
+            format!("d__Bacteria;{};{};{}", family, genus, species)
+        };
+        let mut metadata = String::from(
+            "accession\tcheckm_completeness\tcheckm_contamination\tgtdb_representative\t\
+            gtdb_genome_representative\tgtdb_taxonomy\tncbi_taxid\n",
+        );
+        for (accession, representative, species_representative, completeness, contamination) in
+            BACTERIA_GENOMES
+        {
+            metadata.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t562\n",
+                accession,
+                completeness,
+                contamination,
+                representative,
+                species_representative,
+                lineage(species_representative)
+            ));
+        }
+        let tree_file = dir.join("bac120_r1.tree");
+        let metadata_file = dir.join("bac120_metadata_r1.tsv");
+        fs::write(&tree_file, BACTERIA_TREE).unwrap();
+        fs::write(&metadata_file, metadata).unwrap();
+
+        let files = vec![
+            DomainFile::BacTree(tree_file.to_string_lossy().into_owned()),
+            DomainFile::BacMetadata(metadata_file.to_string_lossy().into_owned()),
+        ];
+        let db = dir.join("release.db");
+        parse_metadata(&db, &files).unwrap();
+        parse_tree(&db, &files).unwrap();
+        db
+    }
+
+    fn release(version: &str) -> ReleaseInfo {
+        ReleaseInfo {
+            version: version.to_string(),
+            date: NaiveDate::from_ymd_opt(2024, 4, 24).unwrap(),
+            sub_versions: Vec::new(),
+        }
+    }
+
+    fn write_index(dir: &Path, version: &str, age_hours: i64) {
+        let index = ReleaseIndex {
+            fetched_at: Utc::now() - Duration::hours(age_hours),
+            releases: vec![release(version)],
+        };
+        index.write_cache(dir).unwrap();
+    }
+
+    /// Loads the index from `dir`, fetching `fetched` if asked to, and returns the version
+    /// of its release and whether it was fetched.
+    fn load(dir: &Path, refresh: bool, fetched: Result<&str, &str>) -> (String, bool) {
+        let cache = ReleaseCache {
+            refresh,
+            ttl: Duration::hours(24),
+        };
+        let called = Cell::new(false);
+        let index = ReleaseIndex::load_with(dir, cache, || {
+            called.set(true);
+            fetched
+                .map(|version| vec![release(version)])
+                .map_err(Into::into)
+        })
+        .unwrap();
+        (index.releases[0].version.clone(), called.get())
+    }
+
+    #[test]
+    fn fresh_cache_is_used_until_it_expires() {
+        let dir = temp_dir("fresh-cache");
+        write_index(&dir, "cached", 1);
+        assert_eq!(
+            load(&dir, false, Ok("fetched")),
+            ("cached".to_string(), false)
+        );
+
+        write_index(&dir, "cached", 25);
+        assert_eq!(
+            load(&dir, false, Ok("fetched")),
+            ("fetched".to_string(), true)
+        );
+        // The fetched index is cached again.
+        assert_eq!(
+            load(&dir, false, Err("offline")),
+            ("fetched".to_string(), false)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refresh_fetches_a_fresh_cache_again() {
+        let dir = temp_dir("refresh");
+        write_index(&dir, "cached", 1);
+        assert_eq!(
+            load(&dir, true, Ok("fetched")),
+            ("fetched".to_string(), true)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stale_cache_is_used_when_fetching_fails() {
+        let dir = temp_dir("stale-cache");
+        write_index(&dir, "cached", 25);
+        assert_eq!(
+            load(&dir, false, Err("offline")),
+            ("cached".to_string(), true)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_cache_is_fetched_again() {
+        let dir = temp_dir("corrupt-cache");
+        fs::write(dir.join(RELEASE_INDEX_FILE), "{ not json").unwrap();
+        assert!(ReleaseIndex::read_cache(&dir).is_none());
+        assert_eq!(
+            load(&dir, false, Ok("fetched")),
+            ("fetched".to_string(), true)
+        );
+        assert!(ReleaseIndex::read_cache(&dir).is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failing_to_write_the_cache_keeps_the_fetched_index() {
+        // The cache directory cannot be created below a file.
+        let dir = temp_dir("unwritable-cache");
+        let file = dir.join("file");
+        fs::write(&file, "").unwrap();
+        assert_eq!(
+            load(&file.join("taxo"), false, Ok("fetched")),
+            ("fetched".to_string(), true)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn absent_support_is_told_apart_from_zero() {
+        let support = |node_str| parse_tree_node(node_str).unwrap().1;
+        assert_eq!(support("'100.0:p__Firmicutes; c__Bacilli':0.1"), 100.0);
+        assert_eq!(support("0.0:0.1"), 0.0);
+        assert_eq!(support("A:0.1[0.0]"), 0.0);
+        assert!(support("RS_GCF_000005845.2:0.1").is_nan());
+        assert!(support("'d__Bacteria'").is_nan());
+        assert!(support("").is_nan());
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// One input item, with an optional custom tip label.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputRecord {
+    pub item: String,
+    pub label: Option<String>,
+}
+
+impl InputRecord {
+    pub fn new(item: &str, label: Option<&str>) -> Self {
+        InputRecord {
+            item: item.trim().to_string(),
+            label: label
+                .map(str::trim)
+                .filter(|label| !label.is_empty())
+                .map(str::to_string),
+        }
+    }
+}
+
+/// Reads input items from a file, or from stdin when `path` is `-`.
+///
+/// See [`parse_input`] for the accepted formats.
+pub fn read_input(
+    path: &Path,
+    column: Option<&str>,
+    label_column: Option<&str>,
+) -> io::Result<Vec<InputRecord>> {
+    let text = if path == Path::new("-") {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text)?;
+        text
+    } else {
+        fs::read_to_string(path)?
+    };
+    parse_input(&text, column, label_column)
+}
+
+/// Parses input items.
+///
+/// Everything after a `#` is a comment, and blank lines are ignored. Without `column`,
+/// items are separated by newlines, commas or tabs. With `column`, the input is a table
+/// with a header row, tab- or comma-delimited, and items are read from the named column
+/// (or 1-based column number), labels from `label_column` if given.
+pub fn parse_input(
+    text: &str,
+    column: Option<&str>,
+    label_column: Option<&str>,
+) -> io::Result<Vec<InputRecord>> {
+    let lines: Vec<&str> = text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim_end_matches('\r'))
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+
+    let mut records = Vec::new();
+    match column {
+        None => {
+            for line in lines {
+                records.extend(
+                    line.split([',', '\t'])
+                        .map(|item| InputRecord::new(item, None)),
+                );
+            }
+        }
+        Some(column) => {
+            let Some((header, rows)) = lines.split_first() else {
+                return Ok(records);
+            };
+            let delimiter = if header.contains('\t') { '\t' } else { ',' };
+            let header_fields: Vec<&str> = header.split(delimiter).map(str::trim).collect();
+            let item_index = column_index(&header_fields, column)?;
+            let label_index = label_column
+                .map(|label| column_index(&header_fields, label))
+                .transpose()?;
+
+            for row in rows {
+                let fields: Vec<&str> = row.split(delimiter).collect();
+                let item = fields.get(item_index).copied().unwrap_or("");
+                let label = label_index.and_then(|i| fields.get(i).copied());
+                records.push(InputRecord::new(item, label));
+            }
+        }
+    }
+
+    records.retain(|record| !record.item.is_empty());
+    Ok(records)
+}
+
+/// Finds a column by header name, or by 1-based number.
+fn column_index(header_fields: &[&str], column: &str) -> io::Result<usize> {
+    if let Some(index) = header_fields.iter().position(|&field| field == column) {
+        return Ok(index);
+    }
+    match column.parse::<usize>() {
+        Ok(number) if number >= 1 && number <= header_fields.len() => Ok(number - 1),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Column not found in input header: {}", column),
+        )),
+    }
+}
+
+/// A feature-by-sample abundance table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AbundanceTable {
+    pub samples: Vec<String>,
+    /// Row items: GTDB accessions, taxa or NCBI taxids.
+    pub items: Vec<String>,
+    /// `abundances[i][j]` is the abundance of item `i` in sample `j`.
+    pub abundances: Vec<Vec<f64>>,
+}
+
+/// Reads an abundance table from a file, or from stdin when `path` is `-`.
+///
+/// See [`parse_abundance_table`] for the format.
+pub fn read_abundance_table(path: &Path) -> io::Result<AbundanceTable> {
+    let text = if path == Path::new("-") {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text)?;
+        text
+    } else {
+        fs::read_to_string(path)?
+    };
+    parse_abundance_table(&text)
+}
+
+/// Parses a tab- or comma-delimited abundance table with items as rows and samples as
+/// columns, the first row naming the samples and the first column the items.
+///
+/// Lines starting with `#` are comments, except a header such as `#OTU ID<TAB>...` as
+/// written by `biom convert`. Empty cells count as zero.
+pub fn parse_abundance_table(text: &str) -> io::Result<AbundanceTable> {
+    let mut lines = text
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.trim().is_empty())
+        .filter(|line| !line.starts_with('#') || line.contains('\t'));
+    let header = lines
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Empty abundance table"))?;
+    let delimiter = if header.contains('\t') { '\t' } else { ',' };
+    let samples: Vec<String> = header
+        .split(delimiter)
+        .skip(1)
+        .map(|sample| sample.trim().to_string())
+        .collect();
+
+    let mut items = Vec::new();
+    let mut abundances = Vec::new();
+    for line in lines {
+        let mut fields = line.split(delimiter);
+        let item = fields.next().unwrap_or("").trim();
+        let row = fields
+            .map(|field| match field.trim() {
+                "" => Ok(0.0),
+                value => value.parse::<f64>().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Invalid abundance for {}: {}", item, value),
+                    )
+                }),
+            })
+            .collect::<io::Result<Vec<f64>>>()?;
+        if row.len() != samples.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Expected {} abundances for {}, found {}",
+                    samples.len(),
+                    item,
+                    row.len()
+                ),
+            ));
+        }
+        items.push(item.to_string());
+        abundances.push(row);
+    }
+
+    Ok(AbundanceTable {
+        samples,
+        items,
+        abundances,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(records: &[InputRecord]) -> Vec<&str> {
+        records.iter().map(|record| record.item.as_str()).collect()
+    }
+
+    #[test]
+    fn tabs_separate_items_like_commas() {
+        let records = parse_input("a\tb,c\n# comment\n\nd # note\r\n", None, None).unwrap();
+        assert_eq!(items(&records), ["a", "b", "c", "d"]);
+        assert!(records.iter().all(|record| record.label.is_none()));
+    }
+
+    #[test]
+    fn labels_are_read_from_label_column() {
+        let text = "accession\tstrain\nGCF_1.1\tK-12\nGCF_2.1\t\n";
+        let records = parse_input(text, Some("accession"), Some("strain")).unwrap();
+        assert_eq!(
+            records,
+            [
+                InputRecord::new("GCF_1.1", Some("K-12")),
+                InputRecord::new("GCF_2.1", None),
+            ]
+        );
+
+        let records = parse_input(text, Some("1"), None).unwrap();
+        assert_eq!(items(&records), ["GCF_1.1", "GCF_2.1"]);
+        assert!(records.iter().all(|record| record.label.is_none()));
+    }
+
+    #[test]
+    fn unknown_column_is_an_error() {
+        assert!(parse_input("a,b\n1,2\n", Some("c"), None).is_err());
+        assert!(parse_input("a,b\n1,2\n", Some("a"), Some("3")).is_err());
+    }
+}
@@ -1,8 +1,16 @@
 pub mod db;
+pub mod diversity;
+pub mod filter;
 pub mod generate;
 pub mod gtdb;
+pub mod input;
 pub mod ncbi;
+pub mod red;
+pub mod rooting;
+pub mod stats;
+pub mod subsample;
 pub mod taxo;
+pub mod translate;
 pub mod tree;
 pub mod update;
 pub mod utils;
@@ -1,13 +1,34 @@
+use clade::diversity::{nearest_genomes, sets_pd, table_unifrac, DistanceMatrix, MatrixFormat};
+use clade::generate::{
+    build_domain_trees, join_domain_trees, process_data, resolve_domain_representatives,
+    resolve_domain_tips, taxon_subtree, InputOptions, Tip, TreeOptions,
+};
 use clade::gtdb::{
     download_gtdb_data, get_sub_version_files, list_releases, parse_domain_files, parse_metadata,
-    parse_tree,
+    parse_tree, ReleaseCache, ReleaseIndex,
 };
+use clade::input::{read_abundance_table, read_input};
 use clade::ncbi;
+use clade::red::lca_red;
+use clade::rooting::{root_at, root_midpoint, Rooting};
+use clade::stats::{gtdb_tree_stats, taxonomy_stats, TreeStats};
+use clade::subsample::{Preference, Subsample, SubsampleStrategy};
 use clade::taxo::{prune_taxonomy, prune_taxonomy_by_names, Taxonomy};
+use clade::translate::{is_taxid, translate, Direction};
+use clade::tree::{
+    collapse_weak_branches, order_children, to_newick, ChildOrder, GtdbTree, Node,
+    PolytomyResolution,
+};
 use clade::update::update_taxdump;
 use clap::{Parser, Subcommand};
 use std::env;
-use std::{error::Error, fs::File, io::Write, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
 
 #[derive(clap::Parser, Debug)]
 #[clap(author, version, about)]
@@ -38,11 +59,39 @@ enum Command {
     Update,
     #[clap(about = "GTDB related operations")]
     Gtdb {
+        #[clap(
+            long,
+            global = true,
+            help = "Fetch the list of GTDB releases again instead of using the cached one"
+        )]
+        refresh: bool,
+        #[clap(
+            long,
+            global = true,
+            default_value_t = 24,
+            help = "Hours after which the cached list of GTDB releases is fetched again"
+        )]
+        cache_ttl: u32,
         #[clap(subcommand)]
-        subcmd: GtdbSubCommand,
+        subcmd: Box<GtdbSubCommand>,
     },
     #[clap(about = "Generate and print taxonomy summary from taxdump files")]
-    Generate,
+    Generate {
+        #[clap(short, long, help = "Prune to these taxids before summarizing")]
+        taxids: Option<Vec<String>>,
+        #[clap(short, long, help = "Prune to these names before summarizing")]
+        names: Option<Vec<String>>,
+        #[clap(long, help = "Print the summary as JSON")]
+        json: bool,
+        #[clap(
+            long,
+            default_value_t = 10,
+            help = "Number of largest clades to report"
+        )]
+        top: usize,
+        #[clap(short, long, help = "Output file path for the summary")]
+        output: Option<PathBuf>,
+    },
     #[clap(about = "Prune the taxonomy tree and generate Newick format")]
     Prune {
         #[clap(short, long, help = "List of taxids to keep")]
@@ -51,6 +100,34 @@ enum Command {
         names: Option<Vec<String>>,
         #[clap(short, long, help = "Output file path for Newick format")]
         output: PathBuf,
+        #[clap(
+            long,
+            value_name = "ROOTING",
+            help = "Root the pruned tree: midpoint, outgroup (a sister taxon, added to the tree), tip:<taxid|name> or taxon:<taxid|name>"
+        )]
+        root: Option<Rooting>,
+
+        #[clap(
+            long,
+            value_enum,
+            help = "Order of the children of each node, for reproducible output"
+        )]
+        order: Option<ChildOrder>,
+
+        #[clap(
+            long,
+            value_enum,
+            help = "Resolve polytomies into bifurcations joined by zero-length branches"
+        )]
+        resolve_polytomies: Option<PolytomyResolution>,
+
+        #[clap(
+            long,
+            default_value_t = 0,
+            requires = "resolve_polytomies",
+            help = "Seed of random polytomy resolution"
+        )]
+        seed: u64,
     },
 }
 
@@ -80,24 +157,497 @@ enum GtdbSubCommand {
         version: String,
     },
     #[clap(about = "Generate Newick format from GTDB database")]
-    Newick {
-        #[clap(short, long, help = "GTDB tree version to generate Newick format")]
+    Newick(Box<NewickArgs>),
+    #[clap(about = "Extract the GTDB tree clade of a taxon")]
+    Subtree {
+        #[clap(short, long, help = "GTDB tree version to extract the clade from")]
         version: String,
-        #[clap(short, long, help = "Domain to generate Newick format")]
-        domain: String,
 
         #[clap(
             short,
             long,
-            help = "Input file path to the data that needs to be analyzed, format: GCF_7312312.0,s__Fen731 sp002068775"
+            help = "Taxon whose clade is extracted, e.g. g__Escherichia or o__Enterobacterales"
         )]
-        input: PathBuf,
+        taxon: String,
 
         #[clap(short, long, help = "Output file path for Newick format")]
         output: Option<PathBuf>,
+
+        #[clap(
+            long,
+            help = "Collapse internal branches with a lower bootstrap support into polytomies"
+        )]
+        min_support: Option<f64>,
+
+        #[clap(
+            long,
+            help = "Collapse internal branches shorter than this into polytomies"
+        )]
+        min_length: Option<f64>,
+
+        #[clap(
+            long,
+            value_enum,
+            help = "Order of the children of each node, for reproducible output"
+        )]
+        order: Option<ChildOrder>,
+
+        #[clap(
+            long,
+            value_enum,
+            help = "Resolve polytomies into bifurcations joined by zero-length branches"
+        )]
+        resolve_polytomies: Option<PolytomyResolution>,
+
+        #[clap(
+            long,
+            default_value_t = 0,
+            requires = "resolve_polytomies",
+            help = "Seed of random polytomy resolution"
+        )]
+        seed: u64,
+    },
+    #[clap(about = "Translate taxa between GTDB and NCBI taxonomies")]
+    Translate {
+        #[clap(short, long, help = "GTDB version whose translation tables are used")]
+        version: String,
+        #[clap(short, long, value_enum, help = "Translation direction")]
+        direction: Direction,
+        #[clap(
+            short,
+            long,
+            default_value = "s__",
+            help = "Rank to translate NCBI taxids to, as a prefix or a name, e.g. s__, genus"
+        )]
+        rank: String,
+        #[clap(long, help = "Report every candidate instead of the majority only")]
+        all: bool,
+        #[clap(
+            required = true,
+            help = "Taxa to translate, with rank prefix (s__Escherichia coli), or NCBI taxids"
+        )]
+        taxa: Vec<String>,
+        #[clap(short, long, help = "Output file path for the translation table")]
+        output: Option<PathBuf>,
+    },
+    #[clap(about = "Faith's phylogenetic diversity of one or many genome sets")]
+    Pd {
+        #[clap(short, long, help = "GTDB tree version")]
+        version: String,
+
+        #[clap(
+            short,
+            long,
+            help = "Input file path to the genomes or taxa, or - for stdin, in the formats of gtdb newick"
+        )]
+        input: PathBuf,
+
+        #[clap(
+            long,
+            help = "Read inputs from this column (header name or 1-based number) of a TSV or CSV with headers"
+        )]
+        column: Option<String>,
+
+        #[clap(
+            long,
+            requires = "column",
+            help = "Group the inputs into sets by this column, e.g. the sample of a sample-by-genome table"
+        )]
+        set_column: Option<String>,
+
+        #[clap(
+            long,
+            help = "Stop at the LCA of each set instead of including the path to the root"
+        )]
+        unrooted: bool,
+
+        #[clap(
+            long,
+            help = "Compute PD from the inputs that resolve instead of failing on missing ones"
+        )]
+        skip_missing: bool,
+
+        #[clap(short, long, help = "Output file path for the TSV")]
+        output: Option<PathBuf>,
+    },
+    #[clap(about = "Pairwise patristic distances between genomes on the GTDB tree")]
+    Distance {
+        #[clap(short, long, help = "GTDB tree version")]
+        version: String,
+
+        #[clap(
+            short,
+            long,
+            help = "Input file path to the genomes or taxa, or - for stdin, in the formats of gtdb newick"
+        )]
+        input: PathBuf,
+
+        #[clap(
+            long,
+            help = "Read inputs from this column (header name or 1-based number) of a TSV or CSV with headers"
+        )]
+        column: Option<String>,
+
+        #[clap(long, value_enum, default_value = "phylip", help = "Output format")]
+        format: MatrixFormat,
+
+        #[clap(
+            long,
+            help = "Compute distances between the inputs that resolve instead of failing on missing ones"
+        )]
+        skip_missing: bool,
+
+        #[clap(short, long, help = "Output file path for the distances")]
+        output: Option<PathBuf>,
+    },
+    #[clap(about = "UniFrac distances between the samples of an abundance table")]
+    Unifrac {
+        #[clap(short, long, help = "GTDB tree version")]
+        version: String,
+
+        #[clap(
+            short,
+            long,
+            help = "Abundance table, or - for stdin: items (accessions, species, taxids) as rows, samples as columns"
+        )]
+        input: PathBuf,
+
+        #[clap(
+            short,
+            long,
+            help = "Domain tree to use, required when the items span several domains"
+        )]
+        domain: Option<String>,
+
+        #[clap(long, help = "Weighted (normalized) UniFrac instead of unweighted")]
+        weighted: bool,
+
+        #[clap(long, value_enum, default_value = "phylip", help = "Output format")]
+        format: MatrixFormat,
+
+        #[clap(
+            long,
+            help = "Ignore the items that do not resolve to a single genome instead of failing"
+        )]
+        skip_missing: bool,
+
+        #[clap(short, long, help = "Output file path for the distances")]
+        output: Option<PathBuf>,
+    },
+    #[clap(about = "Nearest genomes to a query genome on the GTDB tree")]
+    Neighbors {
+        #[clap(short, long, help = "GTDB tree version")]
+        version: String,
+
+        #[clap(short, long, help = "Query genome accession, e.g. GCF_000005845.2")]
+        accession: String,
+
+        #[clap(short, default_value_t = 10, help = "Number of neighbors to report")]
+        k: usize,
+
+        #[clap(long, help = "Only report species representative genomes")]
+        representatives_only: bool,
+
+        #[clap(
+            long = "where",
+            help = "Genome metadata filter on the neighbors, e.g. \"checkm_completeness>95 AND contamination<5\""
+        )]
+        filter: Option<String>,
+
+        #[clap(short, long, help = "Output file path for the TSV")]
+        output: Option<PathBuf>,
+    },
+    #[clap(about = "Relative evolutionary divergence of the LCA of a set of genomes")]
+    Red {
+        #[clap(short, long, help = "GTDB tree version")]
+        version: String,
+
+        #[clap(
+            short,
+            long,
+            help = "Input file path to the genomes or taxa, or - for stdin, in the formats of gtdb newick"
+        )]
+        input: PathBuf,
+
+        #[clap(
+            long,
+            help = "Read inputs from this column (header name or 1-based number) of a TSV or CSV with headers"
+        )]
+        column: Option<String>,
+
+        #[clap(long, help = "Print the report as JSON")]
+        json: bool,
+
+        #[clap(short, long, help = "Output file path for the report")]
+        output: Option<PathBuf>,
+    },
+    #[clap(about = "Print summary statistics of a GTDB tree")]
+    Stats {
+        #[clap(short, long, help = "GTDB tree version to summarize")]
+        version: String,
+        #[clap(short, long, help = "Domain to summarize")]
+        domain: String,
+        #[clap(long, help = "Print the summary as JSON")]
+        json: bool,
+        #[clap(
+            long,
+            default_value_t = 10,
+            help = "Number of largest clades to report"
+        )]
+        top: usize,
+        #[clap(short, long, help = "Output file path for the summary")]
+        output: Option<PathBuf>,
     },
 }
 
+#[derive(clap::Args, Debug)]
+struct NewickArgs {
+    #[clap(short, long, help = "GTDB tree version to generate Newick format")]
+    version: String,
+    #[clap(
+        short,
+        long,
+        help = "Domain to generate Newick format, detected from the inputs by default"
+    )]
+    domain: Option<String>,
+
+    #[clap(
+        short,
+        long,
+        help = "Input file path to the data that needs to be analyzed, or - for stdin. Comma-, newline- or tab-separated, format: GCF_7312312.0,s__Fen731 sp002068775"
+    )]
+    input: PathBuf,
+
+    #[clap(
+        long,
+        help = "Read inputs from this column (header name or 1-based number) of a TSV or CSV with headers"
+    )]
+    column: Option<String>,
+
+    #[clap(
+        long,
+        requires = "column",
+        help = "Read custom tip labels from this column of the TSV or CSV"
+    )]
+    label_column: Option<String>,
+
+    #[clap(short, long, help = "Output file path for Newick format")]
+    output: Option<PathBuf>,
+
+    #[clap(
+        long = "where",
+        help = "Genome metadata filter, e.g. \"checkm_completeness>95 AND contamination<5\""
+    )]
+    filter: Option<String>,
+
+    #[clap(
+        long,
+        help = "Map every input to its species representative genome, one tip per species"
+    )]
+    representatives_only: bool,
+
+    #[clap(
+        long,
+        help = "Write one tree per domain instead of joining them under a common root"
+    )]
+    split_domains: bool,
+
+    #[clap(
+        long,
+        default_value_t = 1.0,
+        help = "Placeholder branch length from the common root to each domain"
+    )]
+    domain_branch_length: f64,
+
+    #[clap(
+        long,
+        conflicts_with = "representatives_only",
+        help = "Keep every input genome as its own tip; genomes of the same species hang from its representative with zero-length branches"
+    )]
+    genome_tips: bool,
+
+    #[clap(
+        long,
+        requires = "genome_tips",
+        help = "Label genome tips as species|accession so that labels are unique"
+    )]
+    unique_labels: bool,
+
+    #[clap(
+        long,
+        help = "Collapse each clade of this rank (e.g. genus or g__) into one tip labeled taxon|count"
+    )]
+    collapse_to: Option<String>,
+
+    #[clap(
+        long,
+        help = "Reduce each domain tree to this many tips, chosen to maximize phylogenetic diversity"
+    )]
+    subsample: Option<usize>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "greedy-pd",
+        requires = "subsample",
+        help = "Subsampling strategy"
+    )]
+    subsample_strategy: SubsampleStrategy,
+
+    #[clap(
+        long,
+        requires = "subsample",
+        help = "File of inputs whose tips are always kept when subsampling, in the formats of --input"
+    )]
+    keep: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_enum,
+        requires = "subsample",
+        help = "Genomes preferred between otherwise equivalent tips when subsampling"
+    )]
+    prefer: Option<Preference>,
+
+    #[clap(
+        long,
+        value_name = "ROOTING",
+        help = "Root each domain tree: midpoint, outgroup (a genome of the sister clade, added to the tree), tip:<name> or taxon:<name>"
+    )]
+    root: Option<Rooting>,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "Order of the children of each node, for reproducible output"
+    )]
+    order: Option<ChildOrder>,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "Resolve polytomies into bifurcations joined by zero-length branches"
+    )]
+    resolve_polytomies: Option<PolytomyResolution>,
+
+    #[clap(
+        long,
+        default_value_t = 0,
+        requires = "resolve_polytomies",
+        help = "Seed of random polytomy resolution"
+    )]
+    seed: u64,
+
+    #[clap(
+        long,
+        help = "Collapse internal branches with a lower bootstrap support into polytomies"
+    )]
+    min_support: Option<f64>,
+
+    #[clap(
+        long,
+        help = "Collapse internal branches shorter than this into polytomies"
+    )]
+    min_length: Option<f64>,
+
+    #[clap(
+        long,
+        help = "Build the tree from the inputs that resolve instead of failing on missing ones"
+    )]
+    skip_missing: bool,
+
+    #[clap(
+        long,
+        help = "Write a TSV report of how each input was resolved, or why it was not"
+    )]
+    report: Option<PathBuf>,
+}
+
+fn release_cache(refresh: bool, cache_ttl: u32) -> ReleaseCache {
+    ReleaseCache {
+        refresh,
+        ttl: chrono::Duration::hours(cache_ttl.into()),
+    }
+}
+
+/// Error message for a version without downloaded data, checked against the cached
+/// release list. Only the cache is read, so that parsing works offline.
+fn unknown_version(taxo_path: &Path, version: &str) -> String {
+    let known = ReleaseIndex::read_cache(taxo_path)
+        .map(|index| index.sub_version_names())
+        .unwrap_or_default();
+    if known.iter().any(|v| v == version) {
+        format!(
+            "No downloaded data for version {}, run gtdb download --version {} first",
+            version, version
+        )
+    } else if known.is_empty() {
+        format!("No downloaded data for version {}", version)
+    } else {
+        format!(
+            "Unknown version {}, known GTDB versions: {}",
+            version,
+            known.join(", ")
+        )
+    }
+}
+
+/// Path of the database of a parsed version. Fails when it is missing rather than let
+/// `Connection::open` create an empty database.
+fn version_db(taxo_path: &Path, version: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let db = taxo_path.join(format!("{version}.db"));
+    if db.exists() {
+        Ok(db)
+    } else if taxo_path.join(version).is_dir() {
+        Err(format!(
+            "Version {} is not parsed yet, run gtdb parse --version {} first",
+            version, version
+        )
+        .into())
+    } else {
+        Err(unknown_version(taxo_path, version).into())
+    }
+}
+
+fn load_taxonomy(taxo_path: &PathBuf) -> std::io::Result<Taxonomy> {
+    let (taxid_vec, parentid_vec, name_vec, rank_vec, parent_distances) = ncbi::load(taxo_path)?;
+    Ok(Taxonomy::new(
+        taxid_vec,
+        parentid_vec,
+        name_vec,
+        rank_vec,
+        parent_distances,
+    ))
+}
+
+/// Inserts the domain before the extension, `out.nwk` -> `out.bacteria.nwk`.
+fn domain_output_path(output: &Path, domain: &str) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let file_name = match output.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, domain, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, domain),
+    };
+    output.with_file_name(file_name)
+}
+
+fn write_stats(stats: &TreeStats, json: bool, output: Option<PathBuf>) -> std::io::Result<()> {
+    let report = if json {
+        stats.to_json()
+    } else {
+        stats.to_text()
+    };
+    if let Some(output) = output {
+        let mut file = File::create(output)?;
+        file.write_all(report.as_bytes())?;
+    } else {
+        println!("{}", report);
+    }
+    Ok(())
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
     let taxo_path = args.taxo_path;
@@ -105,9 +655,14 @@ fn main() -> Result<(), Box<dyn Error>> {
     let start = std::time::Instant::now();
     match args.cmd {
         Command::Update => update_taxdump(&taxo_path)?,
-        Command::Gtdb { subcmd } => match subcmd {
+        Command::Gtdb {
+            refresh,
+            cache_ttl,
+            subcmd,
+        } => match *subcmd {
             GtdbSubCommand::Sync { version } => {
-                let sub_version_info = list_releases(false, version)?;
+                let cache = release_cache(refresh, cache_ttl);
+                let sub_version_info = list_releases(&taxo_path, cache, false, version)?;
                 println!(
                     "Downloading sub-version: {}, url: {}",
                     &sub_version_info.version, sub_version_info.url
@@ -122,7 +677,8 @@ fn main() -> Result<(), Box<dyn Error>> {
                 parse_tree(&db, &domain_files)?;
             }
             GtdbSubCommand::Download { version } => {
-                let sub_version_info = list_releases(false, version)?;
+                let cache = release_cache(refresh, cache_ttl);
+                let sub_version_info = list_releases(&taxo_path, cache, false, version)?;
                 println!(
                     "Downloading sub-version: {}, url: {}",
                     sub_version_info.version, sub_version_info.url
@@ -133,29 +689,167 @@ fn main() -> Result<(), Box<dyn Error>> {
                 download_gtdb_data(&sub_version_path, &files)?;
             }
             GtdbSubCommand::Parse { version } => {
+                if !taxo_path.join(&version).is_dir() {
+                    return Err(unknown_version(&taxo_path, &version).into());
+                }
                 let db = taxo_path.join(format!("{version}.db"));
                 let domain_files = parse_domain_files(&taxo_path.join(version))?;
                 parse_metadata(&db, &domain_files)?;
                 parse_tree(&db, &domain_files)?;
             }
             GtdbSubCommand::List => {
-                let _ = list_releases(true, None)?;
+                let cache = release_cache(refresh, cache_ttl);
+                let _ = list_releases(&taxo_path, cache, true, None)?;
+            }
+            GtdbSubCommand::Newick(args) => {
+                let NewickArgs {
+                    version,
+                    input,
+                    column,
+                    label_column,
+                    domain,
+                    output,
+                    filter,
+                    representatives_only,
+                    split_domains,
+                    domain_branch_length,
+                    genome_tips,
+                    unique_labels,
+                    collapse_to,
+                    subsample,
+                    subsample_strategy,
+                    keep,
+                    prefer,
+                    root,
+                    order,
+                    resolve_polytomies,
+                    seed,
+                    min_support,
+                    min_length,
+                    skip_missing,
+                    report,
+                } = *args;
+                let db = version_db(&taxo_path, &version)?;
+                eprintln!("Generating Newick format for GTDB version: {version}");
+                let data = read_input(&input, column.as_deref(), label_column.as_deref())?;
+
+                let options = InputOptions {
+                    filter,
+                    representatives_only,
+                    skip_missing,
+                    genome_tips,
+                    unique_labels,
+                };
+                let subsample = match subsample {
+                    Some(size) => {
+                        let mut kept = HashSet::new();
+                        if let Some(keep) = &keep {
+                            let keep = read_input(keep, None, None)?;
+                            let resolved = process_data(keep, &db, &options)?;
+                            for r in resolved.failed() {
+                                eprintln!(
+                                    "Ignoring --keep {} ({}): {}",
+                                    r.input,
+                                    r.kind,
+                                    r.error.as_deref().unwrap_or_default()
+                                );
+                            }
+                            for tip in resolved.tips {
+                                kept.extend(tip.genome);
+                                kept.insert(tip.key);
+                            }
+                        }
+                        Some(Subsample {
+                            size,
+                            strategy: subsample_strategy,
+                            keep: kept,
+                            prefer,
+                        })
+                    }
+                    None => None,
+                };
+                let tree_options = TreeOptions {
+                    collapse_to,
+                    subsample,
+                    root,
+                    order,
+                    resolve_polytomies,
+                    seed,
+                    min_support,
+                    min_length,
+                };
+                let resolved = process_data(data, &db, &options)?;
+                if let Some(report) = &report {
+                    let mut file = File::create(report)?;
+                    file.write_all(resolved.report_tsv().as_bytes())?;
+                }
+                if skip_missing {
+                    for r in resolved.failed() {
+                        eprintln!(
+                            "Skipped {} ({}): {}",
+                            r.input,
+                            r.kind,
+                            r.error.as_deref().unwrap_or_default()
+                        );
+                    }
+                } else {
+                    resolved.ensure_complete()?;
+                }
+                let trees = build_domain_trees(
+                    &db,
+                    &resolved.tips,
+                    domain.as_deref(),
+                    &options,
+                    &tree_options,
+                )?;
+                if split_domains {
+                    for (domain, root) in trees {
+                        let newick = to_newick(&root);
+                        if let Some(output) = &output {
+                            let mut file = File::create(domain_output_path(output, &domain))?;
+                            file.write_all(newick.as_bytes())?;
+                        } else {
+                            println!("{}", newick);
+                        }
+                    }
+                } else {
+                    let newick = join_domain_trees(trees, domain_branch_length)
+                        .map(|mut root| {
+                            // The domain trees are ordered, the domains are not yet.
+                            if let Some(order) = order {
+                                order_children(&mut root, order, &|node| node.node as u64);
+                            }
+                            to_newick(&root)
+                        })
+                        .unwrap_or_default();
+                    if let Some(output) = output {
+                        let mut file = File::create(output)?;
+                        file.write_all(newick.as_bytes())?;
+                    } else {
+                        println!("{}", newick);
+                    }
+                }
             }
-            GtdbSubCommand::Newick {
+            GtdbSubCommand::Subtree {
                 version,
-                input,
-                domain,
+                taxon,
                 output,
+                min_support,
+                min_length,
+                order,
+                resolve_polytomies,
+                seed,
             } => {
-                let db = taxo_path.join(format!("{version}.db"));
-                println!("Generating Newick format for GTDB version: {version}");
-                // Read the input file and parse it into a list based on commas
-                let data = std::fs::read_to_string(&input)?
-                    .split(',')
-                    .map(|s| s.trim().to_string())
-                    .collect::<Vec<String>>();
-
-                let newick = clade::generate::generate_newick_tree(&db, data, &domain)?;
+                let db = version_db(&taxo_path, &version)?;
+                let mut root = taxon_subtree(&db, &taxon)?;
+                collapse_weak_branches(&mut root, min_support, min_length);
+                if let Some(resolution) = resolve_polytomies {
+                    clade::tree::resolve_polytomies(&mut root, resolution, seed);
+                }
+                if let Some(order) = order {
+                    order_children(&mut root, order, &|node| node.node as u64);
+                }
+                let newick = to_newick(&root);
                 if let Some(output) = output {
                     let mut file = File::create(output)?;
                     file.write_all(newick.as_bytes())?;
@@ -163,42 +857,338 @@ fn main() -> Result<(), Box<dyn Error>> {
                     println!("{}", newick);
                 }
             }
+            GtdbSubCommand::Translate {
+                version,
+                direction,
+                rank,
+                all,
+                taxa,
+                output,
+            } => {
+                let db = version_db(&taxo_path, &version)?;
+                let conn = rusqlite::Connection::open(&db)?;
+                // Taxids above species are resolved through the NCBI taxonomy, if present.
+                let ncbi = if direction == Direction::NcbiToGtdb
+                    && taxa.iter().any(|taxon| is_taxid(taxon))
+                {
+                    load_taxonomy(&taxo_path).ok()
+                } else {
+                    None
+                };
+                let mut table =
+                    String::from("source\trank\ttarget\tgenome_count\ttotal_count\tfraction\n");
+                for taxon in &taxa {
+                    let translations =
+                        translate(&conn, direction, taxon, &rank, all, ncbi.as_ref())?;
+                    if translations.is_empty() {
+                        eprintln!("No translation found for {}", taxon);
+                    }
+                    for t in translations {
+                        table.push_str(&format!(
+                            "{}\t{}\t{}\t{}\t{}\t{:.4}\n",
+                            t.source, t.rank, t.target, t.genome_count, t.total_count, t.fraction
+                        ));
+                    }
+                }
+                if let Some(output) = output {
+                    let mut file = File::create(output)?;
+                    file.write_all(table.as_bytes())?;
+                } else {
+                    print!("{}", table);
+                }
+            }
+            GtdbSubCommand::Pd {
+                version,
+                input,
+                column,
+                set_column,
+                unrooted,
+                skip_missing,
+                output,
+            } => {
+                let db = version_db(&taxo_path, &version)?;
+                let data = read_input(&input, column.as_deref(), set_column.as_deref())?;
+                let options = InputOptions {
+                    representatives_only: true,
+                    skip_missing,
+                    ..Default::default()
+                };
+                let set_names: Vec<String> = data
+                    .iter()
+                    .map(|record| record.label.clone().unwrap_or_else(|| "all".to_string()))
+                    .collect();
+                let resolved = process_data(data, &db, &options)?;
+                if skip_missing {
+                    for r in resolved.failed() {
+                        eprintln!(
+                            "Skipped {}: {}",
+                            r.input,
+                            r.error.as_deref().unwrap_or_default()
+                        );
+                    }
+                } else {
+                    resolved.ensure_complete()?;
+                }
+
+                let domains: HashMap<&str, &str> = resolved
+                    .tips
+                    .iter()
+                    .map(|tip| (tip.key.as_str(), tip.domain.as_str()))
+                    .collect();
+                let mut sets: BTreeMap<String, Vec<Tip>> = BTreeMap::new();
+                for (set, resolution) in set_names.into_iter().zip(&resolved.report) {
+                    let tips = sets.entry(set).or_default();
+                    for key in &resolution.resolved {
+                        tips.push(Tip {
+                            key: key.clone(),
+                            domain: domains[key.as_str()].to_string(),
+                            genome: None,
+                            label: None,
+                        });
+                    }
+                }
+
+                let mut table = String::from("set\tdomain\tgenome_count\tpd\n");
+                for row in sets_pd(&db, &sets, !unrooted)? {
+                    table.push_str(&format!(
+                        "{}\t{}\t{}\t{:.6}\n",
+                        row.set, row.domain, row.genome_count, row.pd
+                    ));
+                }
+                if let Some(output) = output {
+                    let mut file = File::create(output)?;
+                    file.write_all(table.as_bytes())?;
+                } else {
+                    print!("{}", table);
+                }
+            }
+            GtdbSubCommand::Distance {
+                version,
+                input,
+                column,
+                format,
+                skip_missing,
+                output,
+            } => {
+                let db = version_db(&taxo_path, &version)?;
+                let data = read_input(&input, column.as_deref(), None)?;
+                // Rows are the input genomes, placed at the tip of their representative
+                let (domain, tips) = resolve_domain_tips(&db, data, skip_missing, true)?;
+                let rows: Vec<(String, String)> = tips
+                    .into_iter()
+                    .map(|tip| (tip.genome.unwrap_or_else(|| tip.key.clone()), tip.key))
+                    .collect();
+                let conn = rusqlite::Connection::open(&db)?;
+                let tree = GtdbTree::load(&conn, &format!("gtdb_tree_{domain}"))?;
+                let matrix = DistanceMatrix::patristic(&tree, &rows);
+                let text = match format {
+                    MatrixFormat::Phylip => matrix.to_phylip(),
+                    MatrixFormat::Tsv => matrix.to_tsv(),
+                };
+                if let Some(output) = output {
+                    let mut file = File::create(output)?;
+                    file.write_all(text.as_bytes())?;
+                } else {
+                    print!("{}", text);
+                }
+            }
+            GtdbSubCommand::Unifrac {
+                version,
+                input,
+                domain,
+                weighted,
+                format,
+                skip_missing,
+                output,
+            } => {
+                let db = version_db(&taxo_path, &version)?;
+                let table = read_abundance_table(&input)?;
+                let matrix = table_unifrac(&db, &table, domain.as_deref(), weighted, skip_missing)?;
+                let text = match format {
+                    MatrixFormat::Phylip => matrix.to_phylip(),
+                    MatrixFormat::Tsv => matrix.to_tsv(),
+                };
+                if let Some(output) = output {
+                    let mut file = File::create(output)?;
+                    file.write_all(text.as_bytes())?;
+                } else {
+                    print!("{}", text);
+                }
+            }
+            GtdbSubCommand::Neighbors {
+                version,
+                accession,
+                k,
+                representatives_only,
+                filter,
+                output,
+            } => {
+                let db = version_db(&taxo_path, &version)?;
+                let neighbors =
+                    nearest_genomes(&db, &accession, k, representatives_only, filter.as_deref())?;
+                let mut table = String::from("accession\tspecies\tdistance\trepresentative\n");
+                for n in neighbors {
+                    table.push_str(&format!(
+                        "{}\t{}\t{:.6}\t{}\n",
+                        n.accession, n.species, n.distance, n.representative
+                    ));
+                }
+                if let Some(output) = output {
+                    let mut file = File::create(output)?;
+                    file.write_all(table.as_bytes())?;
+                } else {
+                    print!("{}", table);
+                }
+            }
+            GtdbSubCommand::Red {
+                version,
+                input,
+                column,
+                json,
+                output,
+            } => {
+                let db = version_db(&taxo_path, &version)?;
+                let data = read_input(&input, column.as_deref(), None)?;
+                let (domain, accessions) = resolve_domain_representatives(&db, data, false)?;
+                let conn = rusqlite::Connection::open(&db)?;
+                let report = lca_red(&conn, &domain, &accessions)?;
+                let report = if json {
+                    report.to_json()
+                } else {
+                    report.to_text()
+                };
+                if let Some(output) = output {
+                    let mut file = File::create(output)?;
+                    file.write_all(report.as_bytes())?;
+                } else {
+                    println!("{}", report);
+                }
+            }
+            GtdbSubCommand::Stats {
+                version,
+                domain,
+                json,
+                top,
+                output,
+            } => {
+                let db = version_db(&taxo_path, &version)?;
+                let stats = gtdb_tree_stats(&db, &domain, top)?;
+                write_stats(&stats, json, output)?;
+            }
         },
-        Command::Generate => ncbi::print_taxonomy_summary(&taxo_path)?,
+        Command::Generate {
+            taxids,
+            names,
+            json,
+            top,
+            output,
+        } => {
+            let taxonomy = load_taxonomy(&taxo_path)?;
+            let stats = if let Some(taxids) = taxids {
+                taxonomy_stats(&prune_taxonomy(&taxonomy, &taxids), top)
+            } else if let Some(names) = names {
+                taxonomy_stats(&prune_taxonomy_by_names(&taxonomy, &names), top)
+            } else {
+                taxonomy_stats(&taxonomy, top)
+            };
+            write_stats(&stats, json, output)?;
+        }
         Command::Prune {
             taxids,
             names,
             output,
+            root,
+            order,
+            resolve_polytomies,
+            seed,
         } => {
-            let (taxid_vec, parentid_vec, name_vec, rank_vec, parent_distances) =
-                ncbi::load(&taxo_path)?;
-            let taxonomy = Taxonomy::new(
-                taxid_vec,
-                parentid_vec,
-                name_vec,
-                rank_vec,
-                parent_distances,
-            );
-
-            let pruned_taxonomy = if let Some(taxids) = taxids {
-                prune_taxonomy(&taxonomy, &taxids)
+            let taxonomy = load_taxonomy(&taxo_path)?;
+
+            let mut taxids = if let Some(taxids) = taxids {
+                taxids
             } else if let Some(names) = names {
-                prune_taxonomy_by_names(&taxonomy, &names)
+                taxonomy.taxids_by_names(&names)
             } else {
                 return Err("Either taxids or names must be provided for pruning".into());
             };
+            let mut outgroup = None;
+            if root == Some(Rooting::Outgroup) {
+                outgroup = taxonomy.suggest_outgroup(&taxids);
+                match &outgroup {
+                    Some(taxid) => {
+                        eprintln!("Outgroup: {}", taxid);
+                        taxids.push(taxid.clone());
+                    }
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "No outgroup found, the taxa span the whole taxonomy",
+                        )
+                        .into())
+                    }
+                }
+            }
+            let pruned_taxonomy = prune_taxonomy(&taxonomy, &taxids);
 
             println!("Original taxonomy size: {}", taxonomy.taxid_vec.len());
             println!("Pruned taxonomy size: {}", pruned_taxonomy.taxid_vec.len());
 
-            let newick = pruned_taxonomy.to_newick();
+            let newick = if root.is_none() && order.is_none() && resolve_polytomies.is_none() {
+                pruned_taxonomy.to_newick()
+            } else {
+                let mut tree = pruned_taxonomy.to_node();
+                if let Some(rooting) = &root {
+                    // A node is matched by taxid, name or name_taxid, spaces as underscores.
+                    let matches = |node: &Node, query: &str| {
+                        let query = query.replace(' ', "_");
+                        [
+                            &pruned_taxonomy.taxid_vec[node.node],
+                            &node.name,
+                            node.label.as_ref().unwrap_or(&node.name),
+                        ]
+                        .iter()
+                        .any(|value| value.replace(' ', "_") == query)
+                    };
+                    let not_found = |what: &str, query: &str| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "Cannot root at {} {}: it is not in the pruned taxonomy",
+                                what, query
+                            ),
+                        )
+                    };
+                    tree = match rooting {
+                        Rooting::Midpoint => root_midpoint(tree),
+                        Rooting::Tip(name) => {
+                            root_at(tree, |node| node.children.is_empty() && matches(node, name))
+                                .ok_or_else(|| not_found("tip", name))?
+                        }
+                        Rooting::Taxon(name) => root_at(tree, |node| matches(node, name))
+                            .ok_or_else(|| not_found("taxon", name))?,
+                        Rooting::Outgroup => {
+                            // The outgroup is set above, or the command has already failed.
+                            let taxid = outgroup.as_deref().unwrap_or_default();
+                            root_at(tree, |node| pruned_taxonomy.taxid_vec[node.node] == taxid)
+                                .ok_or_else(|| not_found("outgroup", taxid))?
+                        }
+                    };
+                }
+                if let Some(resolution) = resolve_polytomies {
+                    clade::tree::resolve_polytomies(&mut tree, resolution, seed);
+                }
+                if let Some(order) = order {
+                    order_children(&mut tree, order, &|node| pruned_taxonomy.taxid_of(node));
+                }
+                pruned_taxonomy.node_to_newick(&tree)
+            };
             let mut file = File::create(output)?;
             file.write_all(newick.as_bytes())?;
             println!("Pruned taxonomy in Newick format with distances written to file.");
         }
     }
     let duration = start.elapsed();
-    println!("Time elapsed: {:?}", duration);
+    eprintln!("Time elapsed: {:?}", duration);
 
     Ok(())
 }
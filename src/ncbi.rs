@@ -66,19 +66,3 @@ pub fn load(
         parent_distances,
     ))
 }
-
-pub fn print_taxonomy_summary(taxo_path: &PathBuf) -> io::Result<()> {
-    let (taxid_vec, parentid_vec, name_vec, rank_vec, parent_distances) = load(taxo_path)?;
-
-    println!("Generated taxonomy summary:");
-    println!("Number of taxa: {}", taxid_vec.len());
-    println!("First 5 entries:");
-    for i in 0..5.min(taxid_vec.len()) {
-        println!(
-            "Index: {}, TaxID: {}, ParentIndex: {}, Name: {}, Rank: {}, ParentDistance: {}",
-            i, taxid_vec[i], parentid_vec[i], name_vec[i], rank_vec[i], parent_distances[i]
-        );
-    }
-
-    Ok(())
-}
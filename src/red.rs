@@ -0,0 +1,194 @@
+use crate::tree::GtdbTree;
+use rusqlite::{params, Connection, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Computes the relative evolutionary divergence (RED) of every node of a tree.
+///
+/// Following Parks et al. (2018), the root has RED 0 and leaves have RED 1. An internal
+/// node `n` with parent `p` has `RED(p) + d / u * (1 - RED(p))`, where `d` is the length
+/// of the branch above `n` and `u` is `d` plus the mean distance from `n` to its leaves.
+/// Values are indexed by position in `tree.nodes`.
+pub fn compute_red(tree: &GtdbTree) -> Vec<f64> {
+    let mut red = vec![0.0; tree.len()];
+    if tree.is_empty() {
+        return red;
+    }
+    let order = tree.preorder(tree.root);
+
+    // Sum of the distances to the leaves below each node, and number of leaves.
+    let mut distance_sums = vec![0.0; tree.len()];
+    let mut leaf_counts = vec![0usize; tree.len()];
+    for &pos in order.iter().rev() {
+        if tree.children[pos].is_empty() {
+            leaf_counts[pos] = 1;
+            continue;
+        }
+        for &child in &tree.children[pos] {
+            leaf_counts[pos] += leaf_counts[child];
+            distance_sums[pos] +=
+                distance_sums[child] + tree.nodes[child].length * leaf_counts[child] as f64;
+        }
+    }
+
+    for &pos in &order {
+        let Some(parent) = tree.parents[pos] else {
+            continue;
+        };
+        red[pos] = if tree.children[pos].is_empty() {
+            1.0
+        } else {
+            let d = tree.nodes[pos].length;
+            let u = d + distance_sums[pos] / leaf_counts[pos] as f64;
+            let ratio = if u > 0.0 { d / u } else { 0.0 };
+            red[parent] + ratio * (1.0 - red[parent])
+        };
+    }
+    red
+}
+
+/// Computes the RED of a `gtdb_tree_{domain}` table and stores it in its `red` column.
+pub fn update_red(conn: &mut Connection, table_name: &str) -> Result<()> {
+    let tree = GtdbTree::load(conn, table_name)?;
+    let red = compute_red(&tree);
+
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(&format!(
+            "UPDATE {} SET red = ?1 WHERE node = ?2",
+            table_name
+        ))?;
+        for (node, red) in tree.nodes.iter().zip(red) {
+            stmt.execute(params![red, node.node])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Median RED of the nodes decorated with each rank, by rank prefix.
+pub fn rank_medians(conn: &Connection, table_name: &str) -> Result<BTreeMap<String, f64>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT t.rank, n.red FROM {}_taxa t JOIN {} n ON n.node = t.node WHERE n.red IS NOT NULL",
+        table_name, table_name
+    ))?;
+    let mut values: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?))
+    })?;
+    for row in rows {
+        let (rank, red) = row?;
+        values.entry(rank).or_default().push(red);
+    }
+
+    Ok(values
+        .into_iter()
+        .map(|(rank, mut reds)| {
+            reds.sort_by(f64::total_cmp);
+            let mid = reds.len() / 2;
+            let median = if reds.len() % 2 == 0 {
+                (reds[mid - 1] + reds[mid]) / 2.0
+            } else {
+                reds[mid]
+            };
+            (rank, median)
+        })
+        .collect())
+}
+
+/// RED of the lowest common ancestor of a set of genomes.
+#[derive(Debug, Clone, Serialize)]
+pub struct RedReport {
+    pub domain: String,
+    pub genome_count: usize,
+    /// Taxa decorating the LCA, empty if undecorated.
+    pub lca_name: String,
+    pub red: f64,
+    /// Median RED of each rank in the reference tree.
+    pub rank_medians: BTreeMap<String, f64>,
+    /// The rank whose median RED is closest to the LCA's.
+    pub probable_rank: Option<String>,
+}
+
+impl RedReport {
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "Domain: {}", self.domain).unwrap();
+        writeln!(out, "Genomes: {}", self.genome_count).unwrap();
+        if !self.lca_name.is_empty() {
+            writeln!(out, "LCA: {}", self.lca_name).unwrap();
+        }
+        writeln!(out, "RED of LCA: {:.4}", self.red).unwrap();
+        writeln!(out, "Median RED per rank:").unwrap();
+        for (rank, median) in &self.rank_medians {
+            writeln!(out, "  {}: {:.4}", rank, median).unwrap();
+        }
+        if let Some(rank) = &self.probable_rank {
+            writeln!(out, "Probable rank: {}", rank).unwrap();
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// Reports the RED of the LCA of the given tree leaves, named by accession.
+pub fn lca_red(
+    conn: &Connection,
+    domain: &str,
+    accessions: &[String],
+) -> Result<RedReport, Box<dyn std::error::Error>> {
+    let table_name = format!("gtdb_tree_{}", domain);
+    let tree = GtdbTree::load(conn, &table_name)?;
+    let leaves = crate::tree::get_leaf_nodes_by_name(conn, &table_name, accessions)?;
+    let positions: Vec<usize> = leaves
+        .iter()
+        .filter_map(|node| tree.index.get(node).copied())
+        .collect();
+    let lca = tree
+        .lca(&positions)
+        .ok_or_else(|| std::io::Error::other("None of the genomes is in the tree"))?;
+
+    let red: f64 = conn
+        .query_row(
+            &format!("SELECT red FROM {} WHERE node = ?1", table_name),
+            [tree.nodes[lca].node],
+            |row| row.get::<_, Option<f64>>(0),
+        )?
+        .ok_or_else(|| {
+            std::io::Error::other("RED is not computed in this database, re-run gtdb parse")
+        })?;
+    let rank_medians = rank_medians(conn, &table_name)?;
+    let probable_rank = rank_medians
+        .iter()
+        .min_by(|a, b| (a.1 - red).abs().total_cmp(&(b.1 - red).abs()))
+        .map(|(rank, _)| rank.clone());
+
+    Ok(RedReport {
+        domain: domain.to_string(),
+        genome_count: positions.len(),
+        lca_name: tree.nodes[lca].name.clone(),
+        red,
+        rank_medians,
+        probable_rank,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::tests::example_tree;
+
+    #[test]
+    fn red_interpolates_between_root_and_leaves() {
+        let red = compute_red(&example_tree());
+        // X: 1 / (1 + 1); Y: 1 / (1 + (1 + 3) / 2).
+        let expected = [0.0, 0.5, 1.0, 1.0, 1.0 / 3.0, 1.0, 1.0];
+        for (value, expected) in red.iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-12, "{:?}", red);
+        }
+    }
+}
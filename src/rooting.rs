@@ -0,0 +1,408 @@
+use crate::tree::Node;
+use std::str::FromStr;
+
+/// Where to root an output tree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rooting {
+    /// At the midpoint of the longest tip-to-tip path.
+    Midpoint,
+    /// On the branch above the tip with this label or name.
+    Tip(String),
+    /// On the branch above the clade of this taxon.
+    Taxon(String),
+    /// On the branch above an outgroup taken from the sister clade in the reference tree,
+    /// added to the output.
+    Outgroup,
+}
+
+impl FromStr for Rooting {
+    type Err = String;
+
+    /// Parses `midpoint`, `outgroup`, `tip:<name>` or `taxon:<name>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("tip", name)) => Ok(Rooting::Tip(name.to_string())),
+            Some(("taxon", name)) => Ok(Rooting::Taxon(name.to_string())),
+            _ => match s {
+                "midpoint" => Ok(Rooting::Midpoint),
+                "outgroup" => Ok(Rooting::Outgroup),
+                _ => Err(format!(
+                    "invalid rooting {}, expected midpoint, outgroup, tip:<name> or taxon:<name>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// A tree as an undirected graph, nodes by preorder position.
+///
+/// Edge attributes (length, support) are those of the node below the edge in the
+/// original orientation. The chain of single-child nodes at the top of a pruned tree is
+/// left out of rerooted trees: `top` is the first node below it with several children.
+struct Graph {
+    nodes: Vec<Node>,
+    parents: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+    top: usize,
+}
+
+impl Graph {
+    fn new(root: Node) -> Self {
+        let mut graph = Graph {
+            nodes: Vec::new(),
+            parents: Vec::new(),
+            children: Vec::new(),
+            top: 0,
+        };
+        let mut stack = vec![(root, None)];
+        while let Some((mut node, parent)) = stack.pop() {
+            let pos = graph.nodes.len();
+            let children = std::mem::take(&mut node.children);
+            graph.nodes.push(node);
+            graph.parents.push(parent);
+            graph.children.push(Vec::new());
+            if let Some(parent) = parent {
+                graph.children[parent].push(pos);
+            }
+            stack.extend(children.into_iter().rev().map(|child| (child, Some(pos))));
+        }
+        while graph.children[graph.top].len() == 1 {
+            graph.top = graph.children[graph.top][0];
+        }
+        graph
+    }
+
+    fn neighbors(&self, pos: usize) -> impl Iterator<Item = usize> + '_ {
+        self.parents[pos]
+            .filter(|&parent| parent >= self.top)
+            .into_iter()
+            .chain(self.children[pos].iter().copied())
+    }
+
+    /// The node whose attributes the edge between two adjacent nodes carries.
+    fn edge(&self, a: usize, b: usize) -> usize {
+        if self.parents[a] == Some(b) {
+            a
+        } else {
+            b
+        }
+    }
+
+    fn is_leaf(&self, pos: usize) -> bool {
+        self.children[pos].is_empty()
+    }
+
+    /// Number of tips below `pos` in the original orientation.
+    fn leaf_count(&self, pos: usize) -> usize {
+        let mut count = 0;
+        let mut stack = vec![pos];
+        while let Some(pos) = stack.pop() {
+            if self.is_leaf(pos) {
+                count += 1;
+            }
+            stack.extend(&self.children[pos]);
+        }
+        count
+    }
+
+    /// Distances from `start` to every node, and the previous node on each path.
+    fn distances(&self, start: usize) -> (Vec<f64>, Vec<Option<usize>>) {
+        let mut distances = vec![f64::INFINITY; self.nodes.len()];
+        let mut previous = vec![None; self.nodes.len()];
+        distances[start] = 0.0;
+        let mut stack = vec![start];
+        while let Some(pos) = stack.pop() {
+            for next in self.neighbors(pos).collect::<Vec<_>>() {
+                if distances[next].is_infinite() {
+                    distances[next] = distances[pos] + self.nodes[self.edge(pos, next)].length;
+                    previous[next] = Some(pos);
+                    stack.push(next);
+                }
+            }
+        }
+        (distances, previous)
+    }
+
+    fn lca(&self, positions: &[usize]) -> Option<usize> {
+        let (&first, rest) = positions.split_first()?;
+        let mut path = vec![first];
+        while let Some(parent) = self.parents[*path.last().unwrap()] {
+            path.push(parent);
+        }
+        let mut lca = 0;
+        for &pos in rest {
+            let mut current = pos;
+            loop {
+                if let Some(i) = path.iter().position(|&p| p == current) {
+                    lca = lca.max(i);
+                    break;
+                }
+                current = self.parents[current]?;
+            }
+        }
+        Some(path[lca])
+    }
+
+    /// Builds the tree rooted on the edge between adjacent `a` and `b`, at `offset` from `a`.
+    fn rooted_on_edge(&self, a: usize, b: usize, offset: f64) -> Node {
+        let edge = &self.nodes[self.edge(a, b)];
+        let offset = offset.clamp(0.0, edge.length);
+        let children = vec![
+            self.build(a, Some(b), offset, edge.bootstrap),
+            self.build(b, Some(a), edge.length - offset, edge.bootstrap),
+        ];
+
+        let old_root = &self.nodes[0];
+        Node {
+            node: old_root.node,
+            parent: old_root.parent,
+            name: String::new(),
+            length: 0.0,
+            bootstrap: None,
+            rank: None,
+            label: None,
+            children,
+        }
+    }
+
+    /// Builds the tree with its original root.
+    fn unchanged(&self) -> Node {
+        self.build(0, None, self.nodes[0].length, self.nodes[0].bootstrap)
+    }
+
+    /// Builds the subtree of `pos` directed away from `from`, below an edge of the given
+    /// length and support.
+    ///
+    /// The old root is removed when it is left with a single child. The traversal is
+    /// iterative, so deep trees do not overflow the stack.
+    fn build(&self, pos: usize, from: Option<usize>, length: f64, bootstrap: Option<f64>) -> Node {
+        // A node being built, with the edge above it, the neighbors left to build below it,
+        // last first, and its built children.
+        struct Frame {
+            pos: usize,
+            from: Option<usize>,
+            length: f64,
+            bootstrap: Option<f64>,
+            pending: Vec<usize>,
+            children: Vec<Node>,
+        }
+        let frame = |pos: usize, from: Option<usize>, length: f64, bootstrap: Option<f64>| {
+            let mut pending: Vec<usize> = self
+                .neighbors(pos)
+                .filter(|&next| Some(next) != from)
+                .collect();
+            pending.reverse();
+            Frame {
+                pos,
+                from,
+                length,
+                bootstrap,
+                pending,
+                children: Vec::new(),
+            }
+        };
+
+        let mut stack = vec![frame(pos, from, length, bootstrap)];
+        loop {
+            let top = stack.last_mut().unwrap();
+            if let Some(next) = top.pending.pop() {
+                let edge = &self.nodes[self.edge(top.pos, next)];
+                let next = frame(next, Some(top.pos), edge.length, edge.bootstrap);
+                stack.push(next);
+                continue;
+            }
+            let mut done = stack.pop().unwrap();
+            let node = if done.from.is_some() && done.pos == self.top && done.children.len() == 1 {
+                let mut child = done.children.pop().unwrap();
+                child.length += done.length;
+                child
+            } else {
+                let mut node = self.nodes[done.pos].clone();
+                node.length = done.length;
+                node.bootstrap = done.bootstrap;
+                node.children = done.children;
+                node
+            };
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(node),
+                None => return node,
+            }
+        }
+    }
+}
+
+/// Roots the tree at the midpoint of its longest tip-to-tip path.
+pub fn root_midpoint(root: Node) -> Node {
+    let graph = Graph::new(root);
+    let Some(first_leaf) = (0..graph.nodes.len()).find(|&pos| graph.is_leaf(pos)) else {
+        return graph.unchanged();
+    };
+    let farthest = |distances: &[f64]| {
+        (0..graph.nodes.len())
+            .filter(|&pos| graph.is_leaf(pos))
+            .max_by(|&x, &y| distances[x].total_cmp(&distances[y]).then(y.cmp(&x)))
+            .unwrap()
+    };
+    let a = farthest(&graph.distances(first_leaf).0);
+    let (distances, previous) = graph.distances(a);
+    let b = farthest(&distances);
+    let half = distances[b] / 2.0;
+
+    // Walk back from b to the edge containing the midpoint.
+    let mut current = b;
+    while let Some(prev) = previous[current] {
+        if distances[prev] <= half {
+            return graph.rooted_on_edge(prev, current, half - distances[prev]);
+        }
+        current = prev;
+    }
+    graph.unchanged()
+}
+
+/// Roots the tree on the branch above the first node, in preorder, matching `is_outgroup`.
+///
+/// The root is placed in the middle of the branch. Returns `None` when no node matches.
+pub fn root_at<F>(root: Node, is_outgroup: F) -> Option<Node>
+where
+    F: Fn(&Node) -> bool,
+{
+    let graph = Graph::new(root);
+    (0..graph.nodes.len())
+        .find(|&pos| is_outgroup(&graph.nodes[pos]))
+        .map(|pos| graph.root_above(pos))
+}
+
+/// Why [`root_at_clade`] could not root a tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CladeError {
+    /// No tip is a member.
+    NoMember,
+    /// Every tip is a member, so no branch separates them from the others.
+    AllMembers,
+    /// The members do not form a clade.
+    NotMonophyletic,
+}
+
+/// Roots the tree on the branch above the clade of the tips matching `is_member`.
+///
+/// When the members span the current root, the tree is rooted above the clade of the
+/// other tips instead, which separates the members all the same.
+pub fn root_at_clade<F>(root: Node, is_member: F) -> Result<Node, CladeError>
+where
+    F: Fn(&Node) -> bool,
+{
+    let graph = Graph::new(root);
+    let (members, others): (Vec<usize>, Vec<usize>) = (0..graph.nodes.len())
+        .filter(|&pos| graph.is_leaf(pos))
+        .partition(|&pos| is_member(&graph.nodes[pos]));
+    if members.is_empty() {
+        return Err(CladeError::NoMember);
+    }
+    if others.is_empty() {
+        return Err(CladeError::AllMembers);
+    }
+    for clade in [&members, &others] {
+        if let Some(lca) = graph.lca(clade) {
+            if lca > graph.top && graph.leaf_count(lca) == clade.len() {
+                return Ok(graph.root_above(lca));
+            }
+        }
+    }
+    Err(CladeError::NotMonophyletic)
+}
+
+impl Graph {
+    /// Roots the tree in the middle of the branch above `pos`, unchanged when `pos` is not
+    /// below the top of the tree.
+    fn root_above(&self, pos: usize) -> Node {
+        match self.parents[pos] {
+            Some(parent) if pos > self.top => {
+                let length = self.nodes[pos].length;
+                self.rooted_on_edge(pos, parent, length / 2.0)
+            }
+            _ => self.unchanged(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::to_newick;
+
+    fn node(name: &str, length: f64, children: Vec<Node>) -> Node {
+        Node {
+            node: 0,
+            parent: 0,
+            name: name.to_string(),
+            length,
+            bootstrap: None,
+            rank: None,
+            label: None,
+            children,
+        }
+    }
+
+    fn leaf(name: &str, length: f64) -> Node {
+        node(name, length, Vec::new())
+    }
+
+    /// ((A:1,B:1):1,(C:1,D:5):1);
+    fn tree() -> Node {
+        node(
+            "",
+            0.0,
+            vec![
+                node("", 1.0, vec![leaf("A", 1.0), leaf("B", 1.0)]),
+                node("", 1.0, vec![leaf("C", 1.0), leaf("D", 5.0)]),
+            ],
+        )
+    }
+
+    #[test]
+    fn midpoint_splits_the_longest_path() {
+        // The longest path is A (or B) to D, of length 8: the midpoint is 4 above D.
+        let root = root_midpoint(tree());
+        assert_eq!(
+            to_newick(&root),
+            "(D:4.000000,((A:1.000000,B:1.000000):2.000000,C:1.000000):1.000000);"
+        );
+    }
+
+    #[test]
+    fn clade_roots_above_its_members() {
+        let root = root_at_clade(tree(), |node| node.name == "C" || node.name == "D").unwrap();
+        assert_eq!(
+            to_newick(&root),
+            "((C:1.000000,D:5.000000):0.500000,(A:1.000000,B:1.000000):1.500000);"
+        );
+    }
+
+    #[test]
+    fn clade_spanning_the_root_roots_above_the_others() {
+        // A, B and C span the root, the tree is rooted above D instead.
+        let root = root_at_clade(tree(), |node| node.name != "D").unwrap();
+        let above_d = root_at(tree(), |node| node.name == "D").unwrap();
+        assert_eq!(to_newick(&root), to_newick(&above_d));
+    }
+
+    #[test]
+    fn deep_trees_are_rooted_without_overflowing_the_stack() {
+        let tree = crate::tree::tests::deep_tree(100_000).subtree(0);
+        let root = root_at(tree, |node| node.name == "T1").unwrap();
+        let root = root_midpoint(root);
+        assert_eq!(root.children.len(), 2);
+    }
+
+    #[test]
+    fn non_monophyletic_clade_is_an_error() {
+        let root = root_at_clade(tree(), |node| node.name == "A" || node.name == "C");
+        assert_eq!(root.unwrap_err(), CladeError::NotMonophyletic);
+        let root = root_at_clade(tree(), |node| node.name == "E");
+        assert_eq!(root.unwrap_err(), CladeError::NoMember);
+        let root = root_at_clade(tree(), |node| node.children.is_empty());
+        assert_eq!(root.unwrap_err(), CladeError::AllMembers);
+        assert!(root_at(tree(), |node| node.name == "E").is_none());
+    }
+}
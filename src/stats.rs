@@ -0,0 +1,328 @@
+use crate::taxo::Taxonomy;
+use crate::tree::GtdbTree;
+use rusqlite::{Connection, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// Ranks used when reading GTDB taxon prefixes such as `g__`.
+const GTDB_RANK_PREFIXES: [(&str, &str); 7] = [
+    ("d__", "domain"),
+    ("p__", "phylum"),
+    ("c__", "class"),
+    ("o__", "order"),
+    ("f__", "family"),
+    ("g__", "genus"),
+    ("s__", "species"),
+];
+
+/// Returns the GTDB prefix of a rank given by name (`genus`) or prefix (`g__`).
+pub fn gtdb_rank_prefix(rank: &str) -> Option<&'static str> {
+    GTDB_RANK_PREFIXES
+        .iter()
+        .find(|(prefix, name)| rank.eq_ignore_ascii_case(prefix) || rank.eq_ignore_ascii_case(name))
+        .map(|(prefix, _)| *prefix)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Clade {
+    pub name: String,
+    pub rank: String,
+    pub leaf_count: usize,
+    pub depth: usize,
+}
+
+/// Summary statistics of a rooted tree.
+#[derive(Debug, Clone, Serialize)]
+pub struct TreeStats {
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub internal_count: usize,
+    pub unary_count: usize,
+    pub max_depth: usize,
+    pub mean_depth: f64,
+    pub rank_counts: BTreeMap<String, usize>,
+    pub largest_clades: Vec<Clade>,
+}
+
+impl TreeStats {
+    /// Computes the statistics from a parent index vector.
+    ///
+    /// `parents[i]` is `None` for roots. Depths are counted in edges from the root
+    /// and averaged over the leaves. `ranks[i]` lists every rank node `i` carries,
+    /// and only named, non-root nodes are considered for the largest clades.
+    pub fn compute(
+        parents: &[Option<usize>],
+        names: &[String],
+        ranks: &[Vec<&str>],
+        top: usize,
+    ) -> Self {
+        let n = parents.len();
+        let mut child_count = vec![0usize; n];
+        for parent in parents.iter().flatten() {
+            child_count[*parent] += 1;
+        }
+
+        // Breadth-first order from the roots, so that parents come before children.
+        let mut offsets = vec![0usize; n + 1];
+        for i in 0..n {
+            offsets[i + 1] = offsets[i] + child_count[i];
+        }
+        let mut children = vec![0usize; offsets[n]];
+        let mut fill = offsets.clone();
+        for (i, parent) in parents.iter().enumerate() {
+            if let Some(p) = parent {
+                children[fill[*p]] = i;
+                fill[*p] += 1;
+            }
+        }
+        let mut order: Vec<usize> = (0..n).filter(|&i| parents[i].is_none()).collect();
+        let mut depth = vec![0usize; n];
+        let mut head = 0;
+        while head < order.len() {
+            let node = order[head];
+            head += 1;
+            for &child in &children[offsets[node]..offsets[node + 1]] {
+                depth[child] = depth[node] + 1;
+                order.push(child);
+            }
+        }
+
+        let mut leaves_under = vec![0usize; n];
+        for &node in order.iter().rev() {
+            if child_count[node] == 0 {
+                leaves_under[node] = 1;
+            }
+            if let Some(p) = parents[node] {
+                leaves_under[p] += leaves_under[node];
+            }
+        }
+
+        let leaf_count = child_count.iter().filter(|&&c| c == 0).count();
+        let unary_count = child_count.iter().filter(|&&c| c == 1).count();
+        let leaf_depth_sum: usize = (0..n)
+            .filter(|&i| child_count[i] == 0)
+            .map(|i| depth[i])
+            .sum();
+        let mean_depth = if leaf_count > 0 {
+            leaf_depth_sum as f64 / leaf_count as f64
+        } else {
+            0.0
+        };
+
+        let mut rank_counts = BTreeMap::new();
+        for node_ranks in ranks {
+            for rank in node_ranks {
+                *rank_counts.entry(rank.to_string()).or_insert(0) += 1;
+            }
+        }
+
+        let mut clades: Vec<usize> = (0..n)
+            .filter(|&i| parents[i].is_some() && child_count[i] > 0 && !names[i].is_empty())
+            .collect();
+        clades.sort_by(|&a, &b| {
+            leaves_under[b]
+                .cmp(&leaves_under[a])
+                .then_with(|| names[a].cmp(&names[b]))
+        });
+        let largest_clades = clades
+            .into_iter()
+            .take(top)
+            .map(|i| Clade {
+                name: names[i].clone(),
+                rank: ranks[i].last().copied().unwrap_or("no rank").to_string(),
+                leaf_count: leaves_under[i],
+                depth: depth[i],
+            })
+            .collect();
+
+        TreeStats {
+            node_count: n,
+            leaf_count,
+            internal_count: n - leaf_count,
+            unary_count,
+            max_depth: depth.iter().copied().max().unwrap_or(0),
+            mean_depth,
+            rank_counts,
+            largest_clades,
+        }
+    }
+
+    /// Renders the statistics as a human readable report.
+    pub fn to_text(&self) -> String {
+        let mut report = String::new();
+        writeln!(report, "Tree summary:").unwrap();
+        writeln!(report, "Number of nodes: {}", self.node_count).unwrap();
+        writeln!(report, "Number of leaves: {}", self.leaf_count).unwrap();
+        writeln!(report, "Number of internal nodes: {}", self.internal_count).unwrap();
+        writeln!(report, "Number of unary nodes: {}", self.unary_count).unwrap();
+        writeln!(report, "Maximum depth: {}", self.max_depth).unwrap();
+        writeln!(report, "Mean leaf depth: {:.2}", self.mean_depth).unwrap();
+        writeln!(report, "Nodes per rank:").unwrap();
+        for (rank, count) in &self.rank_counts {
+            writeln!(report, "  {}: {}", rank, count).unwrap();
+        }
+        writeln!(report, "Largest clades:").unwrap();
+        for clade in &self.largest_clades {
+            writeln!(
+                report,
+                "  {} ({}): {} leaves, depth {}",
+                clade.name, clade.rank, clade.leaf_count, clade.depth
+            )
+            .unwrap();
+        }
+        report
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Failed to serialize tree statistics")
+    }
+}
+
+/// Computes the statistics of an NCBI taxonomy, pruned or not.
+pub fn taxonomy_stats(taxonomy: &Taxonomy, top: usize) -> TreeStats {
+    let parents: Vec<Option<usize>> = taxonomy
+        .parentid_vec
+        .iter()
+        .enumerate()
+        .map(|(i, &p)| if p == i { None } else { Some(p) })
+        .collect();
+    let ranks: Vec<Vec<&str>> = taxonomy
+        .rank_vec
+        .iter()
+        .map(|rank| vec![rank.as_str()])
+        .collect();
+    TreeStats::compute(&parents, &taxonomy.name_vec, &ranks, top)
+}
+
+/// Computes the statistics of a `gtdb_tree_{domain}` table.
+///
+/// Leaves are counted under the `genome` rank, undecorated internal nodes under `no rank`.
+pub fn gtdb_tree_stats(db: &PathBuf, domain: &str, top: usize) -> Result<TreeStats> {
+    let conn = Connection::open(db)?;
+    let table_name = format!("gtdb_tree_{}", domain);
+    let tree = GtdbTree::load(&conn, &table_name)?;
+
+    // Ranks assigned to each node, from `gtdb_tree_{domain}_taxa`, in lineage order.
+    let mut assigned: Vec<Vec<&str>> = vec![Vec::new(); tree.len()];
+    let mut stmt = conn.prepare(&format!(
+        "SELECT node, rank FROM {}_taxa ORDER BY rowid",
+        table_name
+    ))?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, usize>(0)?, row.get::<_, String>(1)?))
+    })?;
+    for row in rows {
+        let (node, prefix) = row?;
+        let rank = GTDB_RANK_PREFIXES
+            .iter()
+            .find(|(rank_prefix, _)| *rank_prefix == prefix)
+            .map(|(_, rank)| *rank);
+        if let (Some(&pos), Some(rank)) = (tree.index.get(&node), rank) {
+            assigned[pos].push(rank);
+        }
+    }
+
+    let names: Vec<String> = tree.nodes.iter().map(|node| node.name.clone()).collect();
+    let ranks: Vec<Vec<&str>> = assigned
+        .into_iter()
+        .enumerate()
+        .map(|(i, ranks)| {
+            if tree.children[i].is_empty() {
+                vec!["genome"]
+            } else if ranks.is_empty() {
+                vec!["no rank"]
+            } else {
+                ranks
+            }
+        })
+        .collect();
+
+    Ok(TreeStats::compute(&tree.parents, &names, &ranks, top))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// root(1) -> Bacteria(2) -> {Escherichia(3) -> {coli(4), albertii(5)}, Salmonella(6)}
+    /// -> enterica(7), as an NCBI taxonomy.
+    fn taxonomy() -> Taxonomy {
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+        Taxonomy::new(
+            strings(&["1", "2", "561", "562", "563", "590", "28901"]),
+            vec![0, 0, 1, 2, 2, 1, 5],
+            strings(&[
+                "root",
+                "Bacteria",
+                "Escherichia",
+                "Escherichia coli",
+                "Escherichia albertii",
+                "Salmonella",
+                "Salmonella enterica",
+            ]),
+            strings(&[
+                "no rank",
+                "superkingdom",
+                "genus",
+                "species",
+                "species",
+                "genus",
+                "species",
+            ]),
+            vec![0.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0],
+        )
+    }
+
+    #[test]
+    fn taxonomy_summary() {
+        let stats = taxonomy_stats(&taxonomy(), 2);
+        assert_eq!(stats.node_count, 7);
+        assert_eq!(stats.leaf_count, 3);
+        assert_eq!(stats.internal_count, 4);
+        // The root and Salmonella have a single child.
+        assert_eq!(stats.unary_count, 2);
+        assert_eq!(stats.max_depth, 3);
+        assert_eq!(stats.mean_depth, 3.0);
+        assert_eq!(stats.rank_counts["species"], 3);
+        assert_eq!(stats.rank_counts["genus"], 2);
+
+        // The root is not a clade; ties are broken by name.
+        let clades: Vec<(&str, &str, usize, usize)> = stats
+            .largest_clades
+            .iter()
+            .map(|c| (c.name.as_str(), c.rank.as_str(), c.leaf_count, c.depth))
+            .collect();
+        assert_eq!(
+            clades,
+            [
+                ("Bacteria", "superkingdom", 3, 1),
+                ("Escherichia", "genus", 2, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn nodes_carrying_several_ranks_count_under_each() {
+        let parents = [None, Some(0), Some(1), Some(1)];
+        let names: Vec<String> = ["", "p__Firmicutes; c__Bacilli", "A", "B"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        let ranks = [
+            vec!["no rank"],
+            vec!["phylum", "class"],
+            vec!["genome"],
+            vec!["genome"],
+        ];
+        let stats = TreeStats::compute(&parents, &names, &ranks, 5);
+        assert_eq!(stats.rank_counts["phylum"], 1);
+        assert_eq!(stats.rank_counts["class"], 1);
+        assert_eq!(stats.rank_counts["genome"], 2);
+        // A clade is reported under its most specific rank.
+        assert_eq!(stats.largest_clades.len(), 1);
+        assert_eq!(stats.largest_clades[0].rank, "class");
+        assert_eq!(stats.mean_depth, 2.0);
+    }
+}
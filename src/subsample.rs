@@ -0,0 +1,358 @@
+use crate::tree::Node;
+use rusqlite::{Connection, Result};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// How tips are chosen when subsampling a tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SubsampleStrategy {
+    /// Greedily add the tip adding the most phylogenetic diversity.
+    GreedyPd,
+    /// Repeatedly drop the tip with the shortest pendant branch.
+    LongestBranch,
+}
+
+/// Which genomes win ties between otherwise equivalent tips.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Preference {
+    /// GTDB species representatives.
+    Representatives,
+    /// Highest `checkm_completeness - 5 * checkm_contamination`.
+    Quality,
+}
+
+/// Options of [`subsample_tree`].
+#[derive(Debug, Clone)]
+pub struct Subsample {
+    pub size: usize,
+    pub strategy: SubsampleStrategy,
+    /// Tip names or species that are always kept.
+    pub keep: HashSet<String>,
+    pub prefer: Option<Preference>,
+}
+
+/// The tips of a tree flattened in preorder, parents before children.
+struct Flat<'a> {
+    nodes: Vec<&'a Node>,
+    parents: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+    leaves: Vec<usize>,
+}
+
+impl<'a> Flat<'a> {
+    fn new(root: &'a Node) -> Self {
+        let mut flat = Flat {
+            nodes: Vec::new(),
+            parents: Vec::new(),
+            children: Vec::new(),
+            leaves: Vec::new(),
+        };
+        let mut stack = vec![(root, None)];
+        while let Some((node, parent)) = stack.pop() {
+            let pos = flat.nodes.len();
+            flat.nodes.push(node);
+            flat.parents.push(parent);
+            flat.children.push(Vec::new());
+            if let Some(parent) = parent {
+                flat.children[parent].push(pos);
+            }
+            if node.children.is_empty() {
+                flat.leaves.push(pos);
+            }
+            stack.extend(node.children.iter().rev().map(|child| (child, Some(pos))));
+        }
+        flat
+    }
+}
+
+/// Reduces the tree to `options.size` tips, keeping the tips of `options.keep`.
+///
+/// Tips that are not kept are removed, along with the internal nodes left without tips.
+/// Trees with at most `size` tips are left unchanged. Ties between tips are broken by the
+/// preference, then by tree order, so the result is deterministic.
+pub fn subsample_tree(conn: &Connection, root: &mut Node, options: &Subsample) -> Result<()> {
+    let flat = Flat::new(root);
+    if flat.leaves.len() <= options.size {
+        return Ok(());
+    }
+
+    let scores = match options.prefer {
+        Some(prefer) => tip_scores(conn, &flat, prefer)?,
+        None => vec![0.0; flat.nodes.len()],
+    };
+    let forced: Vec<usize> = flat
+        .leaves
+        .iter()
+        .copied()
+        .filter(|&pos| {
+            let node = flat.nodes[pos];
+            options.keep.contains(&node.name)
+                || node
+                    .rank
+                    .as_ref()
+                    .is_some_and(|rank| options.keep.contains(rank))
+        })
+        .collect();
+    if forced.len() > options.size {
+        eprintln!(
+            "Keeping {} forced tips, more than the subsample size {}",
+            forced.len(),
+            options.size
+        );
+    }
+
+    let kept = match options.strategy {
+        SubsampleStrategy::GreedyPd => greedy_pd(&flat, &forced, &scores, options.size),
+        SubsampleStrategy::LongestBranch => longest_branch(&flat, &forced, &scores, options.size),
+    };
+    let kept: HashSet<usize> = kept.into_iter().collect();
+    let kept: Vec<bool> = flat.leaves.iter().map(|pos| kept.contains(pos)).collect();
+    retain_tips(root, &kept);
+    Ok(())
+}
+
+/// Scores tips by preference, higher is better.
+fn tip_scores(conn: &Connection, flat: &Flat, prefer: Preference) -> Result<Vec<f64>> {
+    let names: Vec<String> = flat
+        .leaves
+        .iter()
+        .map(|&pos| flat.nodes[pos].name.clone())
+        .collect();
+    let query = match prefer {
+        Preference::Representatives => {
+            "SELECT accession, COALESCE(gtdb_representative, 0) FROM genome_metadata WHERE accession IN ({keys})"
+        }
+        Preference::Quality => {
+            "SELECT accession, COALESCE(checkm_completeness, 0) - 5 * COALESCE(checkm_contamination, 0)
+            FROM genome_metadata WHERE accession IN ({keys})"
+        }
+    };
+    let scores: HashMap<String, f64> = crate::db::query_keys(conn, query, &names, |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?))
+    })?
+    .into_iter()
+    .collect();
+
+    let mut tip_scores = vec![0.0; flat.nodes.len()];
+    for &pos in &flat.leaves {
+        tip_scores[pos] = scores.get(&flat.nodes[pos].name).copied().unwrap_or(0.0);
+    }
+    Ok(tip_scores)
+}
+
+/// Greedy PD maximization: starting from the forced tips, repeatedly adds the tip whose
+/// path to the already covered part of the tree is the longest.
+fn greedy_pd(flat: &Flat, forced: &[usize], scores: &[f64], size: usize) -> Vec<usize> {
+    let mut covered = vec![false; flat.nodes.len()];
+    covered[0] = true;
+    let cover = |covered: &mut Vec<bool>, pos: usize| {
+        let mut current = Some(pos);
+        while let Some(pos) = current {
+            if covered[pos] {
+                break;
+            }
+            covered[pos] = true;
+            current = flat.parents[pos];
+        }
+    };
+
+    let mut kept: Vec<usize> = forced.to_vec();
+    for &pos in forced {
+        cover(&mut covered, pos);
+    }
+    let mut selected = vec![false; flat.nodes.len()];
+    for &pos in &kept {
+        selected[pos] = true;
+    }
+
+    // Length of the uncovered path above each node, computed parents first.
+    let mut gains = vec![0.0; flat.nodes.len()];
+    while kept.len() < size {
+        for pos in 0..flat.nodes.len() {
+            gains[pos] = if covered[pos] {
+                0.0
+            } else {
+                flat.nodes[pos].length + flat.parents[pos].map_or(0.0, |parent| gains[parent])
+            };
+        }
+        let best = flat
+            .leaves
+            .iter()
+            .copied()
+            .filter(|&pos| !selected[pos])
+            .max_by(|&a, &b| {
+                gains[a]
+                    .total_cmp(&gains[b])
+                    .then(scores[a].total_cmp(&scores[b]))
+                    .then(b.cmp(&a))
+            });
+        let Some(best) = best else {
+            break;
+        };
+        selected[best] = true;
+        kept.push(best);
+        cover(&mut covered, best);
+    }
+    kept
+}
+
+/// Longest-branch subsampling: repeatedly removes the unforced tip with the shortest
+/// pendant branch, a tip left alone under its parent inheriting the parent's branch.
+fn longest_branch(flat: &Flat, forced: &[usize], scores: &[f64], size: usize) -> Vec<usize> {
+    let is_forced: HashSet<usize> = forced.iter().copied().collect();
+    let mut remaining_children: Vec<usize> = flat.children.iter().map(Vec::len).collect();
+    let mut removed = vec![false; flat.nodes.len()];
+
+    let pendant = |remaining_children: &[usize], pos: usize| {
+        let mut length = flat.nodes[pos].length;
+        let mut current = flat.parents[pos];
+        while let Some(parent) = current {
+            if remaining_children[parent] != 1 {
+                break;
+            }
+            length += flat.nodes[parent].length;
+            current = flat.parents[parent];
+        }
+        length
+    };
+
+    // Min-heap on (pendant length, score, tree order). Pendant branches only grow, so an
+    // entry whose length is out of date is pushed back with the current one.
+    #[derive(PartialEq)]
+    struct Entry(f64, f64, usize);
+    impl Eq for Entry {}
+    impl PartialOrd for Entry {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+    impl Ord for Entry {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            other
+                .0
+                .total_cmp(&self.0)
+                .then(other.1.total_cmp(&self.1))
+                .then(self.2.cmp(&other.2))
+        }
+    }
+
+    let mut heap: BinaryHeap<Entry> = flat
+        .leaves
+        .iter()
+        .filter(|pos| !is_forced.contains(pos))
+        .map(|&pos| Entry(pendant(&remaining_children, pos), scores[pos], pos))
+        .collect();
+    let mut remaining = flat.leaves.len();
+
+    while remaining > size {
+        let Some(Entry(length, score, pos)) = heap.pop() else {
+            break;
+        };
+        let current_length = pendant(&remaining_children, pos);
+        if current_length != length {
+            heap.push(Entry(current_length, score, pos));
+            continue;
+        }
+        removed[pos] = true;
+        remaining -= 1;
+
+        // Internal nodes left without children are removed too.
+        let mut current = flat.parents[pos];
+        while let Some(parent) = current {
+            remaining_children[parent] -= 1;
+            if remaining_children[parent] > 0 {
+                break;
+            }
+            current = flat.parents[parent];
+        }
+    }
+
+    flat.leaves
+        .iter()
+        .copied()
+        .filter(|&pos| !removed[pos])
+        .collect()
+}
+
+/// Removes the tips whose preorder index is not in `kept`, and the internal nodes left
+/// without tips.
+fn retain_tips(root: &mut Node, kept: &[bool]) {
+    // Tips are visited left to right, in the order of `kept`.
+    let mut next_leaf = 0;
+    crate::tree::fold_tree(root, |node, keep: Vec<bool>| {
+        if node.children.is_empty() {
+            next_leaf += 1;
+            return kept[next_leaf - 1];
+        }
+        let mut keep = keep.into_iter();
+        node.children.retain(|_| keep.next().unwrap());
+        !node.children.is_empty()
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::tests::example_tree;
+    use crate::tree::to_newick;
+
+    fn subsample(strategy: SubsampleStrategy, size: usize, keep: &[&str]) -> String {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut root = example_tree().subtree(0);
+        let options = Subsample {
+            size,
+            strategy,
+            keep: keep.iter().map(|name| name.to_string()).collect(),
+            prefer: None,
+        };
+        subsample_tree(&conn, &mut root, &options).unwrap();
+        to_newick(&root)
+    }
+
+    #[test]
+    fn greedy_pd_adds_the_longest_paths() {
+        assert_eq!(
+            subsample(SubsampleStrategy::GreedyPd, 2, &[]),
+            "((A:1.000000)X:1.000000,(D:3.000000)Y:1.000000)R;"
+        );
+        assert_eq!(
+            subsample(SubsampleStrategy::GreedyPd, 2, &["C"]),
+            "((C:1.000000,D:3.000000)Y:1.000000)R;"
+        );
+    }
+
+    #[test]
+    fn longest_branch_drops_the_shortest_pendant_branches() {
+        assert_eq!(
+            subsample(SubsampleStrategy::LongestBranch, 2, &[]),
+            "((A:1.000000)X:1.000000,(D:3.000000)Y:1.000000)R;"
+        );
+        assert_eq!(
+            subsample(SubsampleStrategy::LongestBranch, 3, &["C"]),
+            "((A:1.000000)X:1.000000,(C:1.000000,D:3.000000)Y:1.000000)R;"
+        );
+    }
+
+    #[test]
+    fn deep_trees_are_subsampled_without_overflowing_the_stack() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut root = crate::tree::tests::deep_tree(100_000).subtree(0);
+        let options = Subsample {
+            size: 2,
+            strategy: SubsampleStrategy::LongestBranch,
+            keep: HashSet::from(["T1".to_string()]),
+            prefer: None,
+        };
+        subsample_tree(&conn, &mut root, &options).unwrap();
+        let newick = to_newick(&root);
+        assert_eq!(newick.matches('T').count(), 2);
+        assert!(newick.starts_with("(T1:1.000000,"));
+    }
+
+    #[test]
+    fn small_trees_are_unchanged() {
+        assert_eq!(
+            subsample(SubsampleStrategy::GreedyPd, 4, &[]),
+            to_newick(&example_tree().subtree(0))
+        );
+    }
+}
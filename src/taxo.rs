@@ -1,4 +1,6 @@
+use crate::tree::Node;
 use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
 
 pub struct Taxonomy {
     pub taxid_vec: Vec<String>,
@@ -79,12 +81,16 @@ impl Taxonomy {
     }
 
     pub fn prune_by_names(&self, names: &[String]) -> Self {
-        let taxids: Vec<String> = names
+        self.prune_by_taxids(&self.taxids_by_names(names))
+    }
+
+    /// The taxids of the given names, names not in the taxonomy are left out.
+    pub fn taxids_by_names(&self, names: &[String]) -> Vec<String> {
+        names
             .iter()
             .filter_map(|name| self.name_vec.iter().position(|n| n == name))
             .map(|index| self.taxid_vec[index].clone())
-            .collect();
-        self.prune_by_taxids(&taxids)
+            .collect()
     }
 
     fn add_ancestors_to_keep(&self, keep_indices: &mut HashSet<usize>, index: usize) {
@@ -99,6 +105,139 @@ impl Taxonomy {
         }
     }
 
+    /// The taxid and the taxids of all its descendants, `None` if it is not in the taxonomy.
+    pub fn descendants(&self, taxid: &str) -> Option<Vec<String>> {
+        let start = self.taxid_vec.iter().position(|id| id == taxid)?;
+        let mut children = vec![Vec::new(); self.taxid_vec.len()];
+        for (i, &p) in self.parentid_vec.iter().enumerate() {
+            if i != p {
+                children[p].push(i);
+            }
+        }
+        let mut taxids = Vec::new();
+        let mut stack = vec![start];
+        while let Some(index) = stack.pop() {
+            taxids.push(self.taxid_vec[index].clone());
+            stack.extend(&children[index]);
+        }
+        Some(taxids)
+    }
+
+    /// Suggests an outgroup for the given taxids: a sister taxon of their lowest common
+    /// ancestor. Returns `None` when the ancestor is the root or has no sister.
+    pub fn suggest_outgroup(&self, taxids: &[String]) -> Option<String> {
+        let indices: Vec<usize> = taxids
+            .iter()
+            .filter_map(|taxid| self.taxid_vec.iter().position(|id| id == taxid))
+            .collect();
+        let (&first, rest) = indices.split_first()?;
+
+        let ancestors = |index: usize| {
+            let mut path = vec![index];
+            let mut current = index;
+            while self.parentid_vec[current] != current {
+                current = self.parentid_vec[current];
+                path.push(current);
+            }
+            path
+        };
+        let mut lca_path = ancestors(first);
+        for &index in rest {
+            let path: HashSet<usize> = ancestors(index).into_iter().collect();
+            let shared = lca_path.iter().position(|i| path.contains(i))?;
+            lca_path.drain(..shared);
+        }
+
+        let lca = lca_path[0];
+        let parent = self.parentid_vec[lca];
+        if parent == lca {
+            return None;
+        }
+        (0..self.parentid_vec.len())
+            .find(|&i| self.parentid_vec[i] == parent && i != parent && i != lca)
+            .map(|i| self.taxid_vec[i].clone())
+    }
+
+    /// Converts the taxonomy to a tree, nodes labeled `name_taxid`.
+    pub fn to_node(&self) -> Node {
+        let root_index = self
+            .parentid_vec
+            .iter()
+            .position(|&p| p == self.parentid_vec[p])
+            .expect("Root node not found");
+
+        let mut children = vec![Vec::new(); self.taxid_vec.len()];
+        for (i, &p) in self.parentid_vec.iter().enumerate() {
+            if i != p {
+                children[p].push(i);
+            }
+        }
+
+        // Nodes are built from the tips up, so deep taxonomies do not overflow the stack.
+        let mut order = Vec::new();
+        let mut stack = vec![root_index];
+        while let Some(index) = stack.pop() {
+            order.push(index);
+            stack.extend(children[index].iter().rev());
+        }
+        let mut built: Vec<Option<Node>> = (0..self.taxid_vec.len()).map(|_| None).collect();
+        for &index in order.iter().rev() {
+            let mut node = self.node(index);
+            node.children = children[index]
+                .iter()
+                .filter_map(|&child| built[child].take())
+                .collect();
+            built[index] = Some(node);
+        }
+        built[root_index].take().unwrap()
+    }
+
+    /// The node of a taxonomy index, without children.
+    fn node(&self, node_index: usize) -> Node {
+        let parent = self.parentid_vec[node_index];
+        Node {
+            node: node_index,
+            parent,
+            name: self.name_vec[node_index].clone(),
+            length: if node_index == parent {
+                0.0
+            } else {
+                self.parent_distances[node_index]
+            },
+            bootstrap: None,
+            rank: None,
+            label: Some(format!(
+                "{}_{}",
+                self.name_vec[node_index], self.taxid_vec[node_index]
+            )),
+            children: Vec::new(),
+        }
+    }
+
+    /// Taxid of a node of [`Taxonomy::to_node`], as a number for ordering.
+    pub fn taxid_of(&self, node: &Node) -> u64 {
+        self.taxid_vec[node.node].parse().unwrap_or(u64::MAX)
+    }
+
+    /// Writes a tree of [`Taxonomy::to_node`] in the format of [`Taxonomy::to_newick`].
+    ///
+    /// Nodes without a label, inserted when rooting or resolving polytomies, are written
+    /// unnamed.
+    pub fn node_to_newick(&self, root: &Node) -> String {
+        let mut newick = String::new();
+        crate::tree::write_newick_nodes(root, &mut newick, |node, newick| {
+            let taxid = &self.taxid_vec[node.node];
+            if node.children.is_empty() {
+                write!(newick, "{}_{}_{}", node.name, taxid, node.length).unwrap();
+            } else if node.label.is_some() {
+                write!(newick, "{}_{}:{}", node.name, taxid, node.length).unwrap();
+            } else {
+                write!(newick, ":{}", node.length).unwrap();
+            }
+        });
+        newick
+    }
+
     pub fn to_newick(&self) -> String {
         let root_index = self
             .parentid_vec
@@ -157,3 +296,44 @@ pub fn prune_taxonomy_by_names(taxonomy: &Taxonomy, names: &[String]) -> Taxonom
 pub fn taxonomy_to_newick(taxonomy: &Taxonomy) -> String {
     taxonomy.to_newick()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolved_polytomies_are_written_unnamed() {
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+        let taxonomy = Taxonomy::new(
+            strings(&["1", "2", "3", "4"]),
+            vec![0, 0, 0, 0],
+            strings(&["root", "a", "b", "c"]),
+            strings(&["no rank", "species", "species", "species"]),
+            vec![0.0, 1.0, 1.0, 1.0],
+        );
+        let mut root = taxonomy.to_node();
+        crate::tree::resolve_polytomies(&mut root, crate::tree::PolytomyResolution::Balanced, 0);
+        assert_eq!(
+            taxonomy.node_to_newick(&root),
+            "(a_2_1,(b_3_1,c_4_1):0)root_1:0"
+        );
+        // The inserted node takes the taxid of the node it resolves.
+        assert_eq!(taxonomy.taxid_of(&root.children[1]), 1);
+    }
+
+    #[test]
+    fn deep_taxonomies_do_not_overflow_the_stack() {
+        // A chain of 100,000 taxa below the root, taxid k being the child of k - 1.
+        let depth = 100_000;
+        let taxonomy = Taxonomy::new(
+            (0..=depth).map(|k| k.to_string()).collect(),
+            (0..=depth).map(|k: usize| k.saturating_sub(1)).collect(),
+            (0..=depth).map(|k| format!("t{}", k)).collect(),
+            vec!["no rank".to_string(); depth + 1],
+            vec![1.0; depth + 1],
+        );
+        let newick = taxonomy.node_to_newick(&taxonomy.to_node());
+        assert_eq!(newick.matches('(').count(), depth);
+        assert!(newick.ends_with(")t1_1:1)t0_0:0"));
+    }
+}
@@ -0,0 +1,327 @@
+use crate::taxo::Taxonomy;
+use rusqlite::{params, Connection, Result};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Direction {
+    GtdbToNcbi,
+    NcbiToGtdb,
+}
+
+impl Direction {
+    fn table_name(&self) -> &'static str {
+        match self {
+            Direction::GtdbToNcbi => "gtdb_to_ncbi",
+            Direction::NcbiToGtdb => "ncbi_to_gtdb",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Translation {
+    pub source: String,
+    pub rank: String,
+    pub target: String,
+    pub genome_count: usize,
+    pub total_count: usize,
+    pub fraction: f64,
+}
+
+/// Counts how GTDB and NCBI taxa co-occur on the same genomes, rank by rank.
+#[derive(Debug, Default)]
+pub struct TranslationCounts {
+    /// (rank, gtdb taxon, ncbi taxon) -> genomes
+    pairs: HashMap<(String, String, String), usize>,
+    /// (rank, gtdb taxon) -> genomes
+    gtdb_totals: HashMap<(String, String), usize>,
+    /// (rank, ncbi taxon) -> genomes
+    ncbi_totals: HashMap<(String, String), usize>,
+}
+
+/// Splits a `d__...;p__...` taxonomy string into `(rank, taxon)` pairs, skipping empty ranks.
+pub(crate) fn taxonomy_ranks(taxonomy: &str) -> Vec<(&str, &str)> {
+    taxonomy
+        .split(';')
+        .map(str::trim)
+        .filter(|taxon| taxon.len() > 3 && taxon.as_bytes()[1..3] == *b"__")
+        .map(|taxon| (&taxon[..3], taxon))
+        .collect()
+}
+
+impl TranslationCounts {
+    /// Records one genome given its GTDB and NCBI taxonomy strings.
+    pub fn add_genome(&mut self, gtdb_taxonomy: &str, ncbi_taxonomy: &str) {
+        let gtdb = taxonomy_ranks(gtdb_taxonomy);
+        let ncbi = taxonomy_ranks(ncbi_taxonomy);
+
+        for (rank, taxon) in &gtdb {
+            *self
+                .gtdb_totals
+                .entry((rank.to_string(), taxon.to_string()))
+                .or_insert(0) += 1;
+        }
+        for (rank, taxon) in &ncbi {
+            *self
+                .ncbi_totals
+                .entry((rank.to_string(), taxon.to_string()))
+                .or_insert(0) += 1;
+        }
+        for (rank, gtdb_taxon) in &gtdb {
+            if let Some((_, ncbi_taxon)) = ncbi.iter().find(|(r, _)| r == rank) {
+                *self
+                    .pairs
+                    .entry((
+                        rank.to_string(),
+                        gtdb_taxon.to_string(),
+                        ncbi_taxon.to_string(),
+                    ))
+                    .or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Writes both translation tables, replacing their previous content.
+    pub fn write(&self, conn: &mut Connection) -> Result<()> {
+        crate::db::create_translation_tables(conn)?;
+
+        let mut gtdb_to_ncbi = Vec::new();
+        let mut ncbi_to_gtdb = Vec::new();
+        for ((rank, gtdb_taxon, ncbi_taxon), &count) in &self.pairs {
+            let gtdb_total = self.gtdb_totals[&(rank.clone(), gtdb_taxon.clone())];
+            let ncbi_total = self.ncbi_totals[&(rank.clone(), ncbi_taxon.clone())];
+            gtdb_to_ncbi.push((
+                rank.clone(),
+                gtdb_taxon.clone(),
+                ncbi_taxon.clone(),
+                count,
+                gtdb_total,
+            ));
+            ncbi_to_gtdb.push((
+                rank.clone(),
+                ncbi_taxon.clone(),
+                gtdb_taxon.clone(),
+                count,
+                ncbi_total,
+            ));
+        }
+
+        crate::db::batch_insert_translation(conn, "gtdb_to_ncbi", &gtdb_to_ncbi)?;
+        crate::db::batch_insert_translation(conn, "ncbi_to_gtdb", &ncbi_to_gtdb)?;
+        Ok(())
+    }
+}
+
+/// Whether a taxon is given as a numeric NCBI taxid.
+pub fn is_taxid(taxon: &str) -> bool {
+    !taxon.is_empty() && taxon.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Translates a taxon name between GTDB and NCBI.
+///
+/// Names carry their rank prefix, e.g. `s__Escherichia coli`. For `ncbi-to-gtdb`, a numeric
+/// NCBI taxid is also accepted and resolved through the genomes carrying it, at `rank`
+/// (a prefix such as `s__` or a name such as `species`). With the NCBI taxonomy, taxids
+/// of any rank are resolved through their descendants. Candidates are sorted by support,
+/// only the majority is returned unless `all` is set.
+pub fn translate(
+    conn: &Connection,
+    direction: Direction,
+    taxon: &str,
+    rank: &str,
+    all: bool,
+    ncbi: Option<&Taxonomy>,
+) -> std::result::Result<Vec<Translation>, Box<dyn std::error::Error>> {
+    let rank = crate::stats::gtdb_rank_prefix(rank)
+        .ok_or_else(|| std::io::Error::other(format!("Unknown rank: {}", rank)))?;
+    let mut translations = if direction == Direction::NcbiToGtdb && is_taxid(taxon) {
+        translate_ncbi_taxid(conn, taxon, rank, ncbi)?
+    } else {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT source, rank, target, genome_count, total_count, fraction
+            FROM {} WHERE source = ?1",
+            direction.table_name()
+        ))?;
+        let rows = stmt.query_map(params![taxon], |row| {
+            Ok(Translation {
+                source: row.get(0)?,
+                rank: row.get(1)?,
+                target: row.get(2)?,
+                genome_count: row.get(3)?,
+                total_count: row.get(4)?,
+                fraction: row.get(5)?,
+            })
+        })?;
+        rows.collect::<Result<Vec<_>>>()?
+    };
+
+    translations.sort_by(|a, b| {
+        b.genome_count
+            .cmp(&a.genome_count)
+            .then_with(|| a.target.cmp(&b.target))
+    });
+    if !all {
+        translations.truncate(1);
+    }
+    Ok(translations)
+}
+
+/// Votes over the GTDB lineages of the genomes annotated with an NCBI taxid.
+///
+/// A genome matches when its own taxid or its NCBI species taxid is the taxid or, given the
+/// NCBI taxonomy, one of its descendants, so strains count towards their species and
+/// species towards their genus.
+fn translate_ncbi_taxid(
+    conn: &Connection,
+    taxid: &str,
+    rank: &str,
+    ncbi: Option<&Taxonomy>,
+) -> std::result::Result<Vec<Translation>, Box<dyn std::error::Error>> {
+    let taxids = ncbi
+        .and_then(|ncbi| ncbi.descendants(taxid))
+        .unwrap_or_else(|| vec![taxid.to_string()]);
+    let mut lineages: HashMap<String, String> = HashMap::new();
+    for query in [
+        "SELECT node, ancestor_sequence FROM genome_taxonomy
+        WHERE rank = 'no rank' AND ncbi_taxid IN ({keys})",
+        "SELECT t.node, t.ancestor_sequence FROM genome_metadata m
+        JOIN genome_taxonomy t ON t.node = m.accession
+        WHERE m.ncbi_species_taxid IN ({keys})",
+    ] {
+        let rows = crate::db::query_keys(conn, query, &taxids, |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        lineages.extend(rows);
+    }
+    if lineages.is_empty() {
+        let hint = if ncbi.is_none() {
+            ", taxids above species need the NCBI taxonomy, see clade update"
+        } else {
+            ""
+        };
+        return Err(format!("No genome is annotated with NCBI taxid {}{}", taxid, hint).into());
+    }
+
+    let total_count = lineages.len();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for lineage in lineages.values() {
+        if let Some((_, taxon)) = taxonomy_ranks(lineage)
+            .into_iter()
+            .find(|(r, _)| *r == rank)
+        {
+            *counts.entry(taxon.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    Ok(counts
+        .into_iter()
+        .map(|(target, genome_count)| Translation {
+            source: taxid.to_string(),
+            rank: rank.to_string(),
+            target,
+            genome_count,
+            total_count,
+            fraction: genome_count as f64 / total_count as f64,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Translation tables of three genomes: two GTDB `s__Escherichia coli` genomes, which
+    /// NCBI classifies as `s__Escherichia coli` and `s__Shigella flexneri`, and one
+    /// `g__Salmonella` genome without a GTDB species.
+    fn tables() -> Connection {
+        let mut counts = TranslationCounts::default();
+        counts.add_genome(
+            "d__Bacteria;g__Escherichia;s__Escherichia coli",
+            "d__Bacteria;g__Escherichia;s__Escherichia coli",
+        );
+        counts.add_genome(
+            "d__Bacteria;g__Escherichia;s__Escherichia coli",
+            "d__Bacteria;g__Shigella;s__Shigella flexneri",
+        );
+        counts.add_genome(
+            "d__Bacteria;g__Salmonella;s__",
+            "d__Bacteria;g__Salmonella;s__Salmonella enterica",
+        );
+        let mut conn = Connection::open_in_memory().unwrap();
+        counts.write(&mut conn).unwrap();
+        conn
+    }
+
+    fn targets(translations: &[Translation]) -> Vec<(&str, usize, usize)> {
+        translations
+            .iter()
+            .map(|t| (t.target.as_str(), t.genome_count, t.total_count))
+            .collect()
+    }
+
+    #[test]
+    fn gtdb_to_ncbi_by_majority() {
+        let conn = tables();
+        let all = translate(
+            &conn,
+            Direction::GtdbToNcbi,
+            "g__Escherichia",
+            "genus",
+            true,
+            None,
+        )
+        .unwrap();
+        // Ties are broken by name.
+        assert_eq!(
+            targets(&all),
+            [("g__Escherichia", 1, 2), ("g__Shigella", 1, 2)]
+        );
+        assert_eq!(all[0].fraction, 0.5);
+
+        let best = translate(
+            &conn,
+            Direction::GtdbToNcbi,
+            "g__Escherichia",
+            "g__",
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(targets(&best), [("g__Escherichia", 1, 2)]);
+    }
+
+    #[test]
+    fn ncbi_to_gtdb() {
+        let conn = tables();
+        let translations = translate(
+            &conn,
+            Direction::NcbiToGtdb,
+            "s__Shigella flexneri",
+            "species",
+            true,
+            None,
+        )
+        .unwrap();
+        assert_eq!(targets(&translations), [("s__Escherichia coli", 1, 1)]);
+
+        // Empty ranks, `s__`, are not translated.
+        let translations = translate(
+            &conn,
+            Direction::NcbiToGtdb,
+            "s__Salmonella enterica",
+            "species",
+            true,
+            None,
+        )
+        .unwrap();
+        assert!(translations.is_empty());
+        assert!(translate(
+            &conn,
+            Direction::NcbiToGtdb,
+            "g__Salmonella",
+            "tribe",
+            true,
+            None
+        )
+        .is_err());
+    }
+}
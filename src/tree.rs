@@ -1,4 +1,5 @@
-use rusqlite::{params, params_from_iter, Connection, Result};
+use rusqlite::{Connection, Result};
+use std::collections::HashMap;
 use std::fmt::Write;
 
 #[derive(Debug, Clone)]
@@ -7,11 +8,24 @@ pub struct Node {
     pub parent: usize,
     pub name: String,
     pub length: f64,
-    pub bootstrap: f64,
+    /// Support of the branch above the node, `None` when the tree gives none.
+    pub bootstrap: Option<f64>,
     pub rank: Option<String>,
+    /// Custom tip label, overriding the name in Newick output.
+    pub label: Option<String>,
     pub children: Vec<Node>,
 }
 
+impl Drop for Node {
+    /// Drops the descendants iteratively, so deep trees do not overflow the stack.
+    fn drop(&mut self) {
+        let mut stack = std::mem::take(&mut self.children);
+        while let Some(mut node) = stack.pop() {
+            stack.append(&mut node.children);
+        }
+    }
+}
+
 impl Node {
     fn from_row(row: &rusqlite::Row<'_>) -> Result<Self> {
         Ok(Node {
@@ -21,140 +35,827 @@ impl Node {
             length: row.get(3)?,
             bootstrap: row.get(4)?,
             rank: row.get(5)?,
+            label: None,
             children: Vec::new(),
         })
     }
 }
 
-pub fn write_node_to_newick(node: &Node, newick: &mut String) {
-    if !node.children.is_empty() {
-        newick.push('(');
-        for (i, child) in node.children.iter().enumerate() {
-            if i > 0 {
-                newick.push(',');
-            }
-            write_node_to_newick(child, newick);
-        }
-        newick.push(')');
-    }
-
+/// The name of a node as written in Newick output.
+pub fn newick_label(node: &Node) -> String {
     // 写入节点名称, 如果name中包含; 则用+代替
     let rank = node.rank.clone().unwrap_or("".to_string());
-    let name = if rank.starts_with("s__") {
+    let name = if let Some(label) = &node.label {
+        label.clone()
+    } else if rank.starts_with("s__") {
         rank
     } else {
-        node.name.clone()
+        // Nodes assigned several taxa, e.g. `p__Firmicutes; c__Bacilli`, are named by the
+        // most specific one.
+        node.name
+            .rsplit(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string()
     };
 
-    let label = name.replace(';', "+").replace(' ', "_");
-    write!(newick, "{}", label).unwrap();
+    name.replace(';', "+").replace(' ', "_")
+}
 
-    // 写入分支长度
-    if node.length != 0.0 {
-        write!(newick, ":{:.6}", node.length).unwrap();
+pub fn write_node_to_newick(node: &Node, newick: &mut String) {
+    write_newick_nodes(node, newick, |node, newick| {
+        write!(newick, "{}", newick_label(node)).unwrap();
+
+        // 写入分支长度
+        if node.length != 0.0 {
+            write!(newick, ":{:.6}", node.length).unwrap();
+        }
+
+        // 写入 bootstrap 值
+        if let Some(bootstrap) = node.bootstrap {
+            write!(newick, "[{:.2}]", bootstrap).unwrap();
+        }
+    });
+}
+
+/// Writes the nesting of a tree in Newick, `write_label` writing what follows each node:
+/// the tip itself, or the closing parenthesis of a clade.
+///
+/// The traversal is iterative, so deep trees do not overflow the stack.
+pub(crate) fn write_newick_nodes<F>(root: &Node, newick: &mut String, write_label: F)
+where
+    F: Fn(&Node, &mut String),
+{
+    enum Step<'a> {
+        Open(&'a Node),
+        Close(&'a Node),
+        Comma,
+    }
+    let mut stack = vec![Step::Open(root)];
+    while let Some(step) = stack.pop() {
+        match step {
+            Step::Open(node) if !node.children.is_empty() => {
+                newick.push('(');
+                stack.push(Step::Close(node));
+                for (i, child) in node.children.iter().enumerate().rev() {
+                    stack.push(Step::Open(child));
+                    if i > 0 {
+                        stack.push(Step::Comma);
+                    }
+                }
+            }
+            Step::Open(node) => write_label(node, newick),
+            Step::Close(node) => {
+                newick.push(')');
+                write_label(node, newick);
+            }
+            Step::Comma => newick.push(','),
+        }
     }
+}
 
-    // 写入 bootstrap 值
-    if node.bootstrap != 0.0 {
-        write!(newick, "[{:.2}]", node.bootstrap).unwrap();
+/// Rebuilds a tree from its tips up: `visit` gets each node with its children already
+/// visited and their results, in child order, and returns the result of the node.
+///
+/// `visit` may replace the children of the node. The traversal is iterative, so deep trees
+/// do not overflow the stack.
+pub(crate) fn fold_tree<T, F>(root: &mut Node, mut visit: F) -> T
+where
+    F: FnMut(&mut Node, Vec<T>) -> T,
+{
+    // A node whose children are being visited, `None` for the root, which stays in place.
+    struct Frame<T> {
+        node: Option<Node>,
+        pending: std::vec::IntoIter<Node>,
+        children: Vec<Node>,
+        results: Vec<T>,
+    }
+    let frame = |node: Option<Node>, children: Vec<Node>| Frame {
+        node,
+        pending: children.into_iter(),
+        children: Vec::new(),
+        results: Vec::new(),
+    };
+
+    let mut stack = vec![frame(None, std::mem::take(&mut root.children))];
+    loop {
+        let top = stack.last_mut().unwrap();
+        if let Some(mut child) = top.pending.next() {
+            let children = std::mem::take(&mut child.children);
+            stack.push(frame(Some(child), children));
+            continue;
+        }
+        let Frame {
+            node,
+            children,
+            results,
+            ..
+        } = stack.pop().unwrap();
+        match node {
+            Some(mut node) => {
+                node.children = children;
+                let result = visit(&mut node, results);
+                let parent = stack.last_mut().unwrap();
+                parent.children.push(node);
+                parent.results.push(result);
+            }
+            None => {
+                root.children = children;
+                return visit(root, results);
+            }
+        }
     }
 }
 
 pub fn get_leaf_nodes_by_rank(
     conn: &Connection,
     table_name: &str,
-    ranks: &Vec<String>,
+    ranks: &[String],
 ) -> Result<Vec<usize>> {
-    let placeholders = vec!["?"; ranks.len()].join(",");
-    let query = format!(
-        "SELECT node FROM {} WHERE rank IN ({})",
-        table_name, placeholders
-    );
-    let mut stmt = conn.prepare(&query)?;
+    let query = format!("SELECT node FROM {} WHERE rank IN ({{keys}})", table_name);
+    crate::db::query_keys(conn, &query, ranks, |row| row.get::<_, usize>(0))
+}
+
+/// A `gtdb_tree_{domain}` table loaded in memory.
+///
+/// Nodes are stored by position in `nodes`, in table order, with their `children`
+/// left empty; the tree structure lives in the `children` index.
+#[derive(Debug, Clone)]
+pub struct GtdbTree {
+    pub nodes: Vec<Node>,
+    pub children: Vec<Vec<usize>>,
+    pub parents: Vec<Option<usize>>,
+    pub index: HashMap<usize, usize>,
+    pub root: usize,
+}
+
+impl GtdbTree {
+    /// Loads the whole tree table with a single query.
+    pub fn load(conn: &Connection, table_name: &str) -> Result<Self> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT node, parent, name, length, bootstrap, rank FROM {} ORDER BY node",
+            table_name
+        ))?;
+        let nodes = stmt
+            .query_map([], Node::from_row)?
+            .collect::<Result<Vec<Node>>>()?;
+
+        Self::from_nodes(nodes).ok_or(rusqlite::Error::QueryReturnedNoRows)
+    }
 
-    let nodes_iter = stmt.query_map(params_from_iter(ranks), |row| row.get::<_, usize>(0))?;
+    /// Builds the children index over nodes whose `parent` refers to other node ids.
+    ///
+    /// The node whose parent is not in the list is the root. Returns `None` without one.
+    pub fn from_nodes(nodes: Vec<Node>) -> Option<Self> {
+        let index: HashMap<usize, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (node.node, i))
+            .collect();
+        let parents: Vec<Option<usize>> = nodes
+            .iter()
+            .map(|node| index.get(&node.parent).copied())
+            .collect();
+        let mut children = vec![Vec::new(); nodes.len()];
+        for (i, parent) in parents.iter().enumerate() {
+            if let Some(p) = parent {
+                children[*p].push(i);
+            }
+        }
+        let root = parents.iter().position(Option::is_none)?;
 
-    let mut nodes = Vec::new();
-    for node_result in nodes_iter {
-        nodes.push(node_result?);
+        Some(GtdbTree {
+            nodes,
+            children,
+            parents,
+            index,
+            root,
+        })
     }
 
-    Ok(nodes)
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Marks the positions of the given node ids; unknown ids are ignored.
+    pub fn select(&self, node_ids: &[usize]) -> Vec<bool> {
+        let mut selected = vec![false; self.len()];
+        for id in node_ids {
+            if let Some(&pos) = self.index.get(id) {
+                selected[pos] = true;
+            }
+        }
+        selected
+    }
+
+    /// Positions of the subtree rooted at `start`, parents before children.
+    pub fn preorder(&self, start: usize) -> Vec<usize> {
+        let mut order = Vec::new();
+        let mut stack = vec![start];
+        while let Some(pos) = stack.pop() {
+            order.push(pos);
+            stack.extend(self.children[pos].iter().rev());
+        }
+        order
+    }
+
+    /// Lowest common ancestor of the given positions, `None` if empty.
+    pub fn lca(&self, positions: &[usize]) -> Option<usize> {
+        let (&first, rest) = positions.split_first()?;
+        let mut path = vec![first];
+        while let Some(parent) = self.parents[*path.last().unwrap()] {
+            path.push(parent);
+        }
+        let path_index: HashMap<usize, usize> =
+            path.iter().enumerate().map(|(i, &pos)| (pos, i)).collect();
+
+        let mut lca = 0;
+        for &pos in rest {
+            let mut current = pos;
+            loop {
+                if let Some(&i) = path_index.get(&current) {
+                    lca = lca.max(i);
+                    break;
+                }
+                current = self.parents[current]?;
+            }
+        }
+        Some(path[lca])
+    }
+
+    /// Builds the nested [`Node`] tree of the whole clade rooted at `start`.
+    pub fn subtree(&self, start: usize) -> Node {
+        let order = self.preorder(start);
+        let mut built: Vec<Option<Node>> = vec![None; self.len()];
+        for &pos in order.iter().rev() {
+            let mut current_node = self.nodes[pos].clone();
+            current_node.children = self.children[pos]
+                .iter()
+                .filter_map(|&child| built[child].take())
+                .collect();
+            built[pos] = Some(current_node);
+        }
+        built[start].take().unwrap()
+    }
+}
+
+/// Prunes the tree to the given leaf node ids and builds the nested [`Node`] tree.
+///
+/// A node is kept if it is one of `leaf_nodes` or has a kept descendant. Specified
+/// internal nodes without kept descendants become tips. The traversal is iterative,
+/// so deep trees do not overflow the stack, and membership is a bitset lookup, so the
+/// whole prune is linear in the size of the tree whatever the number of leaves.
+pub fn build_pruned_tree(tree: &GtdbTree, leaf_nodes: &[usize]) -> Option<Node> {
+    if tree.is_empty() {
+        return None;
+    }
+    let selected = tree.select(leaf_nodes);
+    let order = tree.preorder(tree.root);
+
+    let mut built: Vec<Option<Node>> = vec![None; tree.len()];
+    for &pos in order.iter().rev() {
+        let children: Vec<Node> = tree.children[pos]
+            .iter()
+            .filter_map(|&child| built[child].take())
+            .collect();
+        if !children.is_empty() || selected[pos] {
+            let mut current_node = tree.nodes[pos].clone();
+            current_node.children = children;
+            built[pos] = Some(current_node);
+        }
+    }
+
+    built[tree.root].take()
 }
 
-pub fn _build_pruned_tree(
+pub fn get_leaf_nodes_by_name(
     conn: &Connection,
     table_name: &str,
-    node: usize,
-    leaf_nodes: &Vec<usize>,
-) -> Result<Option<Node>> {
-    let mut stmt = conn.prepare(
-        format!(
-            "SELECT node, parent, name, length, bootstrap, rank FROM {} WHERE node = ?",
-            table_name
-        )
-        .as_str(),
-    )?;
-    let mut current_node = stmt.query_row(params![node], |row| Node::from_row(row))?;
+    names: &[String],
+) -> Result<Vec<usize>> {
+    let query = format!("SELECT node FROM {} WHERE name IN ({{keys}})", table_name);
+    crate::db::query_keys(conn, &query, names, |row| row.get::<_, usize>(0))
+}
+
+/// Sets the custom label of every tip for which `label_of` returns one.
+pub fn label_tips<F>(root: &mut Node, label_of: F)
+where
+    F: Fn(&Node) -> Option<String>,
+{
+    let mut stack = vec![root];
+    while let Some(node) = stack.pop() {
+        if node.children.is_empty() {
+            if let Some(label) = label_of(node) {
+                node.label = Some(label);
+            }
+        } else {
+            stack.extend(node.children.iter_mut());
+        }
+    }
+}
 
-    let mut stmt =
-        conn.prepare(format!("SELECT node FROM {} WHERE parent = ?", table_name).as_str())?;
-    let children_iter = stmt.query_map(params![node], |row| row.get::<_, usize>(0))?;
+/// Collapses every maximal clade whose tips all belong to the same group into one tip.
+///
+/// `group_of` gives the group of a tip, if any. A collapsed clade keeps its own branch
+/// length and is labeled `group|count`, where count is its number of tips.
+pub fn collapse_groups<F>(root: &mut Node, group_of: F)
+where
+    F: Fn(&Node) -> Option<String>,
+{
+    if let (Some(group), count) = fold_tree(root, |node, results| {
+        collapse_children(node, results, &group_of)
+    }) {
+        collapse_node(root, &group, count);
+    }
+}
 
-    let mut keep_node = leaf_nodes.contains(&node);
+/// Collapses the uniform children of `node`, given their groups and tip counts, returning
+/// its group if it is uniform itself.
+fn collapse_children<F>(
+    node: &mut Node,
+    results: Vec<(Option<String>, usize)>,
+    group_of: &F,
+) -> (Option<String>, usize)
+where
+    F: Fn(&Node) -> Option<String>,
+{
+    if node.children.is_empty() {
+        return (group_of(node), 1);
+    }
+    let count = results.iter().map(|(_, count)| count).sum();
+    let first = results[0].0.clone();
+    if first.is_some() && results.iter().all(|(group, _)| *group == first) {
+        return (first, count);
+    }
 
-    for child_result in children_iter {
-        let child_id = child_result?;
-        if let Some(child_node) = build_pruned_tree(conn, table_name, child_id, leaf_nodes)? {
-            current_node.children.push(child_node);
-            keep_node = true;
+    for (child, (group, count)) in node.children.iter_mut().zip(results) {
+        if let Some(group) = group {
+            collapse_node(child, &group, count);
         }
     }
+    (None, count)
+}
 
-    if keep_node {
-        Ok(Some(current_node))
-    } else {
-        Ok(None)
+fn collapse_node(node: &mut Node, group: &str, count: usize) {
+    node.children.clear();
+    node.label = Some(format!("{}|{}", group, count));
+}
+
+/// Turns weak internal branches into polytomies.
+///
+/// An internal branch is weak when its support is below `min_support` or its length below
+/// `min_length`. The branch is contracted: its node is removed, its length dropped and its
+/// children attached to its parent with their own lengths, so distances between tips on
+/// either side of it shrink by its length. Branches without support are only collapsed by
+/// length, and the root is never collapsed.
+pub fn collapse_weak_branches(root: &mut Node, min_support: Option<f64>, min_length: Option<f64>) {
+    let is_weak = |node: &Node| {
+        !node.children.is_empty()
+            && (min_support.is_some_and(|min| node.bootstrap.is_some_and(|support| support < min))
+                || min_length.is_some_and(|min| node.length < min))
+    };
+    fold_tree(root, |node, _: Vec<()>| {
+        let mut children = Vec::with_capacity(node.children.len());
+        for mut child in std::mem::take(&mut node.children) {
+            if is_weak(&child) {
+                children.append(&mut child.children);
+            } else {
+                children.push(child);
+            }
+        }
+        node.children = children;
+    });
+}
+
+/// Order of the children of each node in output trees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChildOrder {
+    /// Smaller clades first.
+    LadderizeUp,
+    /// Larger clades first.
+    LadderizeDown,
+    /// By the first tip label of each clade, alphabetically.
+    Alphabetical,
+    /// By taxid, the node id in GTDB trees.
+    Taxid,
+}
+
+/// Sorts the children of every node, `taxid_of` giving the taxid of a node.
+///
+/// Ties are broken by the first tip label, then the smallest taxid of each clade, so the
+/// order does not depend on the order the tree was built in.
+pub fn order_children<F>(root: &mut Node, order: ChildOrder, taxid_of: &F)
+where
+    F: Fn(&Node) -> u64,
+{
+    fold_tree(root, |node, clades| {
+        order_clade(node, clades, order, taxid_of)
+    });
+}
+
+/// Orders the children of `node` given their clades, returning the tip count, first tip
+/// label and smallest taxid of its own clade.
+fn order_clade<F>(
+    node: &mut Node,
+    clades: Vec<(usize, String, u64)>,
+    order: ChildOrder,
+    taxid_of: &F,
+) -> (usize, String, u64)
+where
+    F: Fn(&Node) -> u64,
+{
+    let taxid = taxid_of(node);
+    if node.children.is_empty() {
+        return (1, newick_label(node), taxid);
     }
+    let mut children: Vec<((usize, String, u64), u64, Node)> = std::mem::take(&mut node.children)
+        .into_iter()
+        .zip(clades)
+        .map(|(child, clade)| (clade, taxid_of(&child), child))
+        .collect();
+    children.sort_by(|(a, a_taxid, _), (b, b_taxid, _)| {
+        let by_clade = (&a.1, a.2).cmp(&(&b.1, b.2));
+        match order {
+            ChildOrder::LadderizeUp => a.0.cmp(&b.0).then(by_clade),
+            ChildOrder::LadderizeDown => b.0.cmp(&a.0).then(by_clade),
+            ChildOrder::Alphabetical => by_clade,
+            ChildOrder::Taxid => a_taxid.cmp(b_taxid).then(by_clade),
+        }
+    });
+
+    let count = children.iter().map(|(clade, _, _)| clade.0).sum();
+    let first = children
+        .iter()
+        .map(|(clade, _, _)| &clade.1)
+        .min()
+        .cloned()
+        .unwrap_or_default();
+    let smallest = children
+        .iter()
+        .map(|(clade, _, _)| clade.2)
+        .fold(taxid, u64::min);
+    node.children = children.into_iter().map(|(_, _, child)| child).collect();
+    (count, first, smallest)
 }
-pub fn build_pruned_tree(
-    conn: &Connection,
-    table_name: &str,
-    node: usize,
-    leaf_nodes: &Vec<usize>,
-) -> Result<Option<Node>> {
-    let mut stmt = conn.prepare(
-        format!(
-            "SELECT node, parent, name, length, bootstrap, rank FROM {} WHERE node = ?",
-            table_name
-        )
-        .as_str(),
-    )?;
-    let mut current_node = stmt.query_row(params![node], |row| Node::from_row(row))?;
 
-    let is_specified_leaf = leaf_nodes.contains(&node);
+/// How polytomies are resolved into bifurcations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PolytomyResolution {
+    /// Joins random pairs of children until two are left.
+    Random,
+    /// Splits the children in halves, recursively, keeping their order.
+    Balanced,
+}
 
-    let mut stmt =
-        conn.prepare(format!("SELECT node FROM {} WHERE parent = ?", table_name).as_str())?;
-    let children_iter = stmt.query_map(params![node], |row| row.get::<_, usize>(0))?;
+/// Resolves every node with more than two children into a binary subtree.
+///
+/// Inserted nodes are unnamed and have zero-length branches, so distances between tips are
+/// unchanged. Random resolution only depends on `seed` and the child order.
+pub fn resolve_polytomies(root: &mut Node, resolution: PolytomyResolution, seed: u64) {
+    let mut rng = SplitMix64(seed);
+    fold_tree(root, |node, _: Vec<()>| {
+        resolve_node(node, resolution, &mut rng)
+    });
+}
 
-    let mut keep_node = false;
-    for child_result in children_iter {
-        let child_id = child_result?;
-        if let Some(child_node) = build_pruned_tree(conn, table_name, child_id, leaf_nodes)? {
-            current_node.children.push(child_node);
-            keep_node = true;
+/// Resolves the polytomy of `node` itself, its children being resolved already.
+fn resolve_node(node: &mut Node, resolution: PolytomyResolution, rng: &mut SplitMix64) {
+    if node.children.len() <= 2 {
+        return;
+    }
+    let mut children = std::mem::take(&mut node.children);
+    node.children = match resolution {
+        PolytomyResolution::Balanced => {
+            let right = children.split_off(children.len() / 2);
+            vec![join_children(node, children), join_children(node, right)]
         }
+        PolytomyResolution::Random => {
+            while children.len() > 2 {
+                let i = rng.below(children.len());
+                let a = children.swap_remove(i);
+                let j = rng.below(children.len());
+                let b = children.swap_remove(j);
+                children.push(inserted_node(node, vec![a, b]));
+            }
+            children
+        }
+    };
+}
+
+/// Balanced binary subtree of `children` below `parent`.
+fn join_children(parent: &Node, mut children: Vec<Node>) -> Node {
+    if children.len() == 1 {
+        return children.pop().unwrap();
     }
+    let right = children.split_off(children.len() / 2);
+    let children = vec![
+        join_children(parent, children),
+        join_children(parent, right),
+    ];
+    inserted_node(parent, children)
+}
 
-    if is_specified_leaf && current_node.children.is_empty() {
-        // 如果是指定的叶子节点，并且没有子节点，则保留
-        Ok(Some(current_node))
-    } else if keep_node {
-        // 如果有需要保留的子节点，则保留当前节点
-        Ok(Some(current_node))
-    } else {
-        // 否则不保留
-        Ok(None)
+fn inserted_node(parent: &Node, children: Vec<Node>) -> Node {
+    Node {
+        node: parent.node,
+        parent: parent.node,
+        name: String::new(),
+        length: 0.0,
+        bootstrap: None,
+        rank: None,
+        label: None,
+        children,
+    }
+}
+
+/// SplitMix64, a small seeded generator whose sequence never changes across versions.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Serializes a tree to a Newick string, terminated by `;`.
+pub fn to_newick(root: &Node) -> String {
+    let mut newick = String::new();
+    write_node_to_newick(root, &mut newick);
+    newick.push(';');
+    newick
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn node(id: usize, parent: usize, name: &str, length: f64) -> Node {
+        Node {
+            node: id,
+            parent,
+            name: name.to_string(),
+            length,
+            bootstrap: None,
+            rank: None,
+            label: None,
+            children: Vec::new(),
+        }
+    }
+
+    /// `((A:1,B:1)X:1,(C:1,D:3)Y:1)R;`, positions in preorder: R, X, A, B, Y, C, D.
+    pub(crate) fn example_tree() -> GtdbTree {
+        GtdbTree::from_nodes(vec![
+            node(1, 0, "R", 0.0),
+            node(2, 1, "X", 1.0),
+            node(3, 2, "A", 1.0),
+            node(4, 2, "B", 1.0),
+            node(5, 1, "Y", 1.0),
+            node(6, 5, "C", 1.0),
+            node(7, 5, "D", 3.0),
+        ])
+        .unwrap()
+    }
+
+    /// A caterpillar of the given depth: each internal node `I{k}` has a tip `T{k}` and the
+    /// next internal node as children, all branches of length 1.
+    pub(crate) fn deep_tree(depth: usize) -> GtdbTree {
+        let mut nodes = Vec::new();
+        for k in 1..=depth {
+            nodes.push(node(k, k - 1, &format!("I{}", k), 1.0));
+            nodes.push(node(depth + k, k, &format!("T{}", k), 1.0));
+        }
+        GtdbTree::from_nodes(nodes).unwrap()
+    }
+
+    #[test]
+    fn deep_trees_do_not_overflow_the_stack() {
+        let tree = deep_tree(100_000);
+        let tips: Vec<usize> = (100_001..=200_000).collect();
+        let mut root = build_pruned_tree(&tree, &tips).unwrap();
+
+        collapse_weak_branches(&mut root, None, Some(0.5));
+        order_children(&mut root, ChildOrder::LadderizeUp, &|node| node.node as u64);
+        resolve_polytomies(&mut root, PolytomyResolution::Random, 1);
+        let newick = to_newick(&root);
+        assert_eq!(newick.matches('(').count(), 100_000);
+        assert!(newick.starts_with("(T1:1.000000,(T2:1.000000,"));
+        assert!(newick.ends_with(")I1:1.000000;"));
+
+        collapse_groups(&mut root, |_| Some("T".to_string()));
+        assert_eq!(to_newick(&root), "T|100000:1.000000;");
+    }
+
+    #[test]
+    fn uniform_clades_collapse_into_one_tip() {
+        let group_of = |node: &Node| match node.name.as_str() {
+            "A" | "B" => Some("g1".to_string()),
+            "C" => Some("g2".to_string()),
+            _ => None,
+        };
+        let mut root = example_tree().subtree(0);
+        collapse_groups(&mut root, group_of);
+        assert_eq!(
+            to_newick(&root),
+            "(g1|2:1.000000,(g2|1:1.000000,D:3.000000)Y:1.000000)R;"
+        );
+
+        let mut root = example_tree().subtree(0);
+        collapse_groups(&mut root, |_| Some("all".to_string()));
+        assert_eq!(to_newick(&root), "all|4;");
+    }
+
+    #[test]
+    fn children_are_ordered_with_ties_broken_by_label() {
+        // `((D,C)Y,F,(B,(E,A)W)Z)R;` without branch lengths.
+        let tree = GtdbTree::from_nodes(vec![
+            node(1, 0, "R", 0.0),
+            node(2, 1, "Y", 0.0),
+            node(3, 2, "D", 0.0),
+            node(4, 2, "C", 0.0),
+            node(5, 1, "F", 0.0),
+            node(6, 1, "Z", 0.0),
+            node(7, 6, "B", 0.0),
+            node(8, 6, "W", 0.0),
+            node(9, 8, "E", 0.0),
+            node(10, 8, "A", 0.0),
+        ])
+        .unwrap();
+        let ordered = |order: ChildOrder, taxid_of: &dyn Fn(&Node) -> u64| {
+            let mut root = tree.subtree(0);
+            order_children(&mut root, order, &taxid_of);
+            to_newick(&root)
+        };
+        let by_id = |node: &Node| node.node as u64;
+
+        assert_eq!(
+            ordered(ChildOrder::LadderizeUp, &by_id),
+            "(F,(C,D)Y,(B,(A,E)W)Z)R;"
+        );
+        assert_eq!(
+            ordered(ChildOrder::LadderizeDown, &by_id),
+            "(((A,E)W,B)Z,(C,D)Y,F)R;"
+        );
+        assert_eq!(
+            ordered(ChildOrder::Alphabetical, &by_id),
+            "(((A,E)W,B)Z,(C,D)Y,F)R;"
+        );
+        assert_eq!(
+            ordered(ChildOrder::Taxid, &by_id),
+            "((D,C)Y,F,(B,(E,A)W)Z)R;"
+        );
+        // Equal taxids fall back to the first tip label.
+        assert_eq!(
+            ordered(ChildOrder::Taxid, &|_| 0),
+            "(((A,E)W,B)Z,(C,D)Y,F)R;"
+        );
+    }
+
+    /// `(A:1,B:1,C:1,D:1,E:1)R;`
+    fn polytomy() -> Node {
+        let mut nodes = vec![node(1, 0, "R", 0.0)];
+        for (i, name) in ["A", "B", "C", "D", "E"].iter().enumerate() {
+            nodes.push(node(i + 2, 1, name, 1.0));
+        }
+        GtdbTree::from_nodes(nodes).unwrap().subtree(0)
+    }
+
+    #[test]
+    fn balanced_resolution_keeps_the_child_order() {
+        let mut root = polytomy();
+        resolve_polytomies(&mut root, PolytomyResolution::Balanced, 0);
+        assert_eq!(
+            to_newick(&root),
+            "((A:1.000000,B:1.000000),(C:1.000000,(D:1.000000,E:1.000000)))R;"
+        );
+    }
+
+    #[test]
+    fn random_resolution_depends_only_on_the_seed() {
+        let resolved = |seed| {
+            let mut root = polytomy();
+            resolve_polytomies(&mut root, PolytomyResolution::Random, seed);
+            root
+        };
+        let root = resolved(42);
+        assert_eq!(to_newick(&root), to_newick(&resolved(42)));
+
+        // Every node is binary, inserted nodes are unnamed, of zero length and carry the
+        // id of the resolved node, and the tips are unchanged.
+        let mut tips = Vec::new();
+        let mut stack = vec![&root];
+        while let Some(node) = stack.pop() {
+            assert!(node.children.len() == 2 || node.children.is_empty());
+            if node.children.is_empty() {
+                tips.push(node.name.as_str());
+            } else if node.name.is_empty() {
+                assert_eq!((node.node, node.length), (1, 0.0));
+            }
+            stack.extend(&node.children);
+        }
+        tips.sort();
+        assert_eq!(tips, ["A", "B", "C", "D", "E"]);
+    }
+
+    #[test]
+    fn lca_of_positions() {
+        let tree = example_tree();
+        assert_eq!(tree.lca(&[2, 3]), Some(1));
+        assert_eq!(tree.lca(&[2, 6]), Some(0));
+        assert_eq!(tree.lca(&[5]), Some(5));
+        assert_eq!(tree.lca(&[]), None);
+    }
+
+    #[test]
+    fn label_of_multi_taxon_node_is_most_specific_taxon() {
+        let mut node = node(1, 0, "p__Firmicutes; c__Bacilli", 0.0);
+        assert_eq!(newick_label(&node), "c__Bacilli");
+        node.rank = Some("s__Escherichia coli".to_string());
+        assert_eq!(newick_label(&node), "s__Escherichia_coli");
+        node.label = Some("K-12".to_string());
+        assert_eq!(newick_label(&node), "K-12");
+    }
+
+    /// Distances between all pairs of tips, for trees with unique node names.
+    fn tip_distances(root: &Node) -> HashMap<(String, String), f64> {
+        // Each tip with the names and root distances of its ancestors and itself.
+        let mut tips: Vec<Vec<(&str, f64)>> = Vec::new();
+        let mut stack = vec![(root, vec![(root.name.as_str(), 0.0)])];
+        while let Some((node, path)) = stack.pop() {
+            if node.children.is_empty() {
+                tips.push(path);
+                continue;
+            }
+            let depth = path.last().unwrap().1;
+            for child in &node.children {
+                let mut child_path = path.clone();
+                child_path.push((child.name.as_str(), depth + child.length));
+                stack.push((child, child_path));
+            }
+        }
+        let mut distances = HashMap::new();
+        for a in &tips {
+            for b in &tips {
+                let (_, lca_depth) = a
+                    .iter()
+                    .zip(b)
+                    .take_while(|(x, y)| x.0 == y.0)
+                    .last()
+                    .unwrap()
+                    .0;
+                let (tip_a, depth_a) = a.last().unwrap();
+                let (tip_b, depth_b) = b.last().unwrap();
+                distances.insert(
+                    (tip_a.to_string(), tip_b.to_string()),
+                    depth_a + depth_b - 2.0 * lca_depth,
+                );
+            }
+        }
+        distances
+    }
+
+    #[test]
+    fn weak_branches_are_contracted() {
+        let mut root = example_tree().subtree(0);
+        root.children[0].bootstrap = Some(0.0);
+        root.children[1].bootstrap = Some(95.0);
+        let before = tip_distances(&root);
+
+        collapse_weak_branches(&mut root, Some(50.0), None);
+        assert_eq!(
+            to_newick(&root),
+            "(A:1.000000,B:1.000000,(C:1.000000,D:3.000000)Y:1.000000[95.00])R;"
+        );
+
+        // Only the distances across the contracted branch X, of length 1, change.
+        let after = tip_distances(&root);
+        for ((a, b), distance) in &before {
+            let across = (["A", "B"].contains(&a.as_str())) != (["A", "B"].contains(&b.as_str()));
+            let expected = if across { distance - 1.0 } else { *distance };
+            assert_eq!(after[&(a.clone(), b.clone())], expected, "{} {}", a, b);
+        }
+
+        // Without support, Y is only collapsed by length.
+        root.children[2].bootstrap = None;
+        collapse_weak_branches(&mut root, Some(50.0), None);
+        assert_eq!(root.children.len(), 3);
+        collapse_weak_branches(&mut root, None, Some(1.5));
+        assert_eq!(
+            to_newick(&root),
+            "(A:1.000000,B:1.000000,C:1.000000,D:3.000000)R;"
+        );
     }
 }